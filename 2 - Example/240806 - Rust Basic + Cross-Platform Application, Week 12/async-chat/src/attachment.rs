@@ -0,0 +1,167 @@
+//! Files shared in groups.
+//!
+//! An attachment travels as a 'FromClient::Offer' (or, when downloading, a
+//! 'FromServer::Offer'), then its contents in 'Chunk's of at most
+//! 'CHUNK_SIZE' bytes, base64-encoded, then 'Complete'. An attachment's id is
+//! the SHA-256 digest of its contents, in lowercase hex, so either side can
+//! check that what arrived is what was sent.
+
+use crate::Attachment;
+use ring::digest;
+
+/// The most bytes of an attachment carried by one 'Chunk', before encoding.
+pub const CHUNK_SIZE: usize = 48 * 1024;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Return the id for an attachment with contents 'data'.
+pub fn digest(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Return true if 'id' looks like an attachment id, and so is safe to use
+/// as a file name.
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == 64
+        && id
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Encode 'data' as standard, padded base64.
+pub fn encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+
+        for i in 0..4 {
+            if i <= group.len() {
+                text.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+/// Decode standard, padded base64.
+pub fn decode(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err("Base64 text is not a multiple of four characters long".to_string());
+    }
+
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, group) in text.chunks(4).enumerate() {
+        let last = n == text.len() / 4 - 1;
+        let padding = group.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("Misplaced base64 padding".to_string());
+        }
+
+        let mut bits = 0u32;
+        for (i, &c) in group[..4 - padding].iter().enumerate() {
+            let value = BASE64
+                .iter()
+                .position(|&b| b == c)
+                .ok_or_else(|| format!("Invalid base64 character '{}'", c as char))?;
+            bits |= (value as u32) << (18 - 6 * i);
+        }
+
+        data.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+
+    Ok(data)
+}
+
+/// Collects an attachment's chunks as they arrive.
+pub struct Download {
+    pub attachment: Attachment,
+    data: Vec<u8>,
+}
+
+impl Download {
+    pub fn new(attachment: Attachment) -> Download {
+        Download {
+            attachment,
+            data: Vec::new(),
+        }
+    }
+
+    /// Add the base64-encoded 'chunk' to what has arrived so far.
+    pub fn push(&mut self, chunk: &str) -> Result<(), String> {
+        let chunk = decode(chunk)?;
+        if self.data.len() + chunk.len() > self.attachment.size as usize {
+            return Err(format!(
+                "'{}' is larger than promised",
+                self.attachment.file_name
+            ));
+        }
+
+        self.data.extend_from_slice(&chunk);
+        Ok(())
+    }
+
+    /// Return the attachment's contents, if they're complete and intact.
+    pub fn finish(self) -> Result<Vec<u8>, String> {
+        if self.data.len() as u64 != self.attachment.size
+            || digest(&self.data) != self.attachment.id
+        {
+            return Err(format!(
+                "'{}' arrived incomplete or damaged",
+                self.attachment.file_name
+            ));
+        }
+
+        Ok(self.data)
+    }
+}
+
+#[test]
+fn test_attachment_encoding() {
+    for (data, text) in [
+        (&b""[..], ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (b"foob", "Zm9vYg=="),
+        (b"\xff\xfe\x00", "//4A"),
+    ] {
+        assert_eq!(encode(data), text);
+        assert_eq!(decode(text).unwrap(), data);
+    }
+    assert!(decode("Zg=").is_err());
+    assert!(decode("Zg==Zg==").is_err());
+    assert!(decode("Z!==").is_err());
+
+    let id = digest(b"abc");
+    assert_eq!(
+        id,
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert!(is_valid_id(&id));
+    assert!(!is_valid_id("../etc/passwd"));
+
+    let attachment = Attachment {
+        id,
+        file_name: "abc.txt".to_string(),
+        size: 3,
+    };
+    let mut download = Download::new(attachment.clone());
+    download.push(&encode(b"ab")).unwrap();
+    download.push(&encode(b"c")).unwrap();
+    assert_eq!(download.finish().unwrap(), b"abc");
+
+    let mut damaged = Download::new(attachment);
+    damaged.push(&encode(b"abd")).unwrap();
+    assert!(damaged.finish().is_err());
+}
@@ -0,0 +1,293 @@
+//! The commands a user can type.
+
+use async_chat::{FromClient, Role};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Each command, with the arguments it takes.
+pub const COMMANDS: &[&str] = &[
+    "join GROUP",
+    "leave GROUP",
+    "post GROUP MESSAGE...",
+    "edit GROUP NUMBER MESSAGE...",
+    "delete GROUP NUMBER",
+    "react GROUP NUMBER REACTION",
+    "dm NICKNAME MESSAGE...",
+    "groups",
+    "members GROUP",
+    "history GROUP [LIMIT [BEFORE]]",
+    "create GROUP [private]",
+    "invite GROUP NICKNAME",
+    "kick GROUP NICKNAME",
+    "ban GROUP NICKNAME",
+    "mod GROUP NICKNAME",
+    "unmod GROUP NICKNAME",
+    "attach GROUP FILE [MESSAGE...]",
+    "download ID",
+];
+
+/// Something the user asked for.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// A request to send to the server as it is.
+    Request(FromClient),
+    /// Share the file at 'path' in 'group_name', along with 'message'. The
+    /// session reads the file, and uploads it in pieces.
+    Attach {
+        group_name: Arc<String>,
+        path: PathBuf,
+        message: Arc<String>,
+    },
+}
+
+/// Parse a command line, such as 'join GROUP' or 'attach GROUP FILE'.
+pub fn parse_line(line: &str) -> Option<Command> {
+    let (command, rest) = get_next_token(line)?;
+
+    if command == "attach" {
+        let (group, rest) = get_next_token(rest)?;
+        let (path, rest) = get_next_token(rest)?;
+
+        Some(Command::Attach {
+            group_name: Arc::new(group.to_string()),
+            path: PathBuf::from(path),
+            message: Arc::new(rest.trim_start().to_string()),
+        })
+    } else {
+        parse_command(line).map(Command::Request)
+    }
+}
+
+/// Parse a command line, such as 'join GROUP', as a request.
+pub fn parse_command(line: &str) -> Option<FromClient> {
+    let (command, rest) = get_next_token(line)?;
+
+    if command == "post" {
+        let (group, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+
+        Some(FromClient::Post {
+            group_name: Arc::new(group.to_string()),
+            message: Arc::new(message),
+            id: None,
+        })
+    } else if command == "edit" {
+        let (group, rest) = get_next_token(rest)?;
+        let (seq, rest) = get_next_token(rest)?;
+
+        Some(FromClient::Edit {
+            group_name: Arc::new(group.to_string()),
+            seq: seq.parse().ok()?,
+            message: Arc::new(rest.trim_start().to_string()),
+        })
+    } else if command == "delete" || command == "react" {
+        let (group, rest) = get_next_token(rest)?;
+        let (seq, rest) = get_next_token(rest)?;
+        let group_name = Arc::new(group.to_string());
+        let seq = seq.parse().ok()?;
+
+        if command == "delete" {
+            if !rest.trim_start().is_empty() {
+                return None;
+            }
+            return Some(FromClient::Delete { group_name, seq });
+        }
+
+        let (reaction, rest) = get_next_token(rest)?;
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+
+        Some(FromClient::React {
+            group_name,
+            seq,
+            reaction: Arc::new(reaction.to_string()),
+        })
+    } else if command == "dm" {
+        let (nickname, rest) = get_next_token(rest)?;
+        let message = rest.trim_start().to_string();
+
+        Some(FromClient::DirectMessage {
+            to: Arc::new(nickname.to_string()),
+            message: Arc::new(message),
+        })
+    } else if command == "join" || command == "leave" || command == "members" {
+        let (group, rest) = get_next_token(rest)?;
+
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+
+        let group_name = Arc::new(group.to_string());
+
+        Some(match command {
+            "join" => FromClient::Join { group_name },
+            "leave" => FromClient::Leave { group_name },
+            _ => FromClient::ListMembers { group_name },
+        })
+    } else if command == "create" {
+        let (group, rest) = get_next_token(rest)?;
+        let private = match rest.trim() {
+            "" => false,
+            "private" => true,
+            _ => return None,
+        };
+
+        Some(FromClient::Create {
+            group_name: Arc::new(group.to_string()),
+            private,
+        })
+    } else if ["invite", "kick", "ban", "mod", "unmod"].contains(&command) {
+        let (group, rest) = get_next_token(rest)?;
+        let (nickname, rest) = get_next_token(rest)?;
+
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+
+        let group_name = Arc::new(group.to_string());
+        let nickname = Arc::new(nickname.to_string());
+
+        Some(match command {
+            "invite" => FromClient::Invite {
+                group_name,
+                nickname,
+            },
+            "kick" => FromClient::Kick {
+                group_name,
+                nickname,
+            },
+            "ban" => FromClient::Ban {
+                group_name,
+                nickname,
+            },
+            "mod" => FromClient::SetRole {
+                group_name,
+                nickname,
+                role: Role::Moderator,
+            },
+            _ => FromClient::SetRole {
+                group_name,
+                nickname,
+                role: Role::Member,
+            },
+        })
+    } else if command == "history" {
+        let (group, rest) = get_next_token(rest)?;
+        let mut numbers = rest.split_whitespace().map(str::parse::<u64>);
+        let limit = numbers.next().transpose().ok()?;
+        let before = numbers.next().transpose().ok()?;
+
+        if numbers.next().is_some() {
+            return None;
+        }
+
+        Some(FromClient::History {
+            group_name: Arc::new(group.to_string()),
+            before,
+            limit: limit.map(|limit| limit as usize),
+        })
+    } else if command == "download" {
+        let (id, rest) = get_next_token(rest)?;
+
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+
+        Some(FromClient::Download { id: id.to_string() })
+    } else if command == "groups" {
+        if !rest.trim_start().is_empty() {
+            return None;
+        }
+
+        Some(FromClient::ListGroups)
+    } else {
+        None
+    }
+}
+
+/// Given a string 'input', return 'Some((token, rest))',
+/// where 'token' is the first run of non-whitespace characters in 'input',
+/// and 'rest' is the rest of the string.
+/// If the string contains no non-whitespace characters, return 'None'.
+pub fn get_next_token(mut input: &str) -> Option<(&str, &str)> {
+    input = input.trim_start();
+
+    if input.is_empty() {
+        return None;
+    }
+
+    match input.find(char::is_whitespace) {
+        Some(space) => Some((&input[0..space], &input[space..])),
+        None => Some((input, "")),
+    }
+}
+
+#[test]
+fn test_parse_command() {
+    let group_name = Arc::new("Dogs".to_string());
+
+    assert_eq!(
+        parse_command("leave Dogs"),
+        Some(FromClient::Leave {
+            group_name: group_name.clone()
+        })
+    );
+    assert_eq!(
+        parse_command("  members   Dogs "),
+        Some(FromClient::ListMembers { group_name })
+    );
+    assert_eq!(parse_command("groups"), Some(FromClient::ListGroups));
+    assert_eq!(parse_command("groups Dogs"), None);
+    assert_eq!(parse_command("leave"), None);
+    assert_eq!(
+        parse_command("history Dogs 10 1722902400000"),
+        Some(FromClient::History {
+            group_name: Arc::new("Dogs".to_string()),
+            before: Some(1_722_902_400_000),
+            limit: Some(10),
+        })
+    );
+    assert_eq!(
+        parse_command("mod Dogs jimb"),
+        Some(FromClient::SetRole {
+            group_name: Arc::new("Dogs".to_string()),
+            nickname: Arc::new("jimb".to_string()),
+            role: Role::Moderator,
+        })
+    );
+    assert_eq!(parse_command("create Dogs secret"), None);
+    assert_eq!(
+        parse_command("edit Dogs 3 good  dog"),
+        Some(FromClient::Edit {
+            group_name: Arc::new("Dogs".to_string()),
+            seq: 3,
+            message: Arc::new("good  dog".to_string()),
+        })
+    );
+    assert_eq!(
+        parse_command("react Dogs 3 woof"),
+        Some(FromClient::React {
+            group_name: Arc::new("Dogs".to_string()),
+            seq: 3,
+            reaction: Arc::new("woof".to_string()),
+        })
+    );
+    assert_eq!(parse_command("delete Dogs three"), None);
+    assert_eq!(parse_command("react Dogs 3 woof arf"), None);
+    assert_eq!(
+        parse_line("attach Dogs rex.jpg  good dog"),
+        Some(Command::Attach {
+            group_name: Arc::new("Dogs".to_string()),
+            path: PathBuf::from("rex.jpg"),
+            message: Arc::new("good dog".to_string()),
+        })
+    );
+    assert_eq!(parse_line("attach Dogs"), None);
+    assert_eq!(
+        parse_line("download abc"),
+        Some(Command::Request(FromClient::Download {
+            id: "abc".to_string()
+        }))
+    );
+}
@@ -0,0 +1,142 @@
+use async_chat::client::ClientOptions;
+use async_chat::runtime;
+use async_chat::tls;
+use async_chat::utils::ChatResult;
+use async_chat::Credential;
+use futures::channel::mpsc;
+use futures_lite::FutureExt as _;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod command;
+mod plain;
+mod session;
+mod terminal;
+mod ui;
+
+use session::{Event, Session};
+use ui::App;
+
+const USAGE: &str = "\
+Usage: client ADDRESS:PORT NICKNAME [OPTIONS]
+
+Options:
+    --codec CODEC     Encode packets as 'json' (the default) or 'msgpack'
+    --tls-ca FILE     Connect over TLS, trusting the PEM certificates in FILE
+    --tls-name NAME   The name the server's certificate was issued for
+                      (default: the host part of ADDRESS)
+    --plain           Read commands a line at a time from the standard input
+                      and print what the server says, instead of running
+                      full-screen. This is the default when the standard
+                      input or output isn't a terminal.
+
+If the server requires accounts, set CHAT_PASSWORD or CHAT_TOKEN in the
+environment to the password or token to log in with.
+
+If the connection is lost after logging in, the client keeps trying to
+reconnect, and rejoins the groups it belonged to.";
+
+/// After the user quits, how long to give the connection to close politely.
+const QUIT_GRACE: Duration = Duration::from_secs(1);
+
+fn main() -> ChatResult<()> {
+    let mut positional = Vec::new();
+    let mut tls_ca = None;
+    let mut tls_name = None;
+    let mut options = ClientOptions::default();
+    let mut plain = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--codec" => options.codec = args.next().expect(USAGE).parse()?,
+            "--tls-ca" => tls_ca = Some(PathBuf::from(args.next().expect(USAGE))),
+            "--tls-name" => tls_name = Some(args.next().expect(USAGE)),
+            "--plain" => plain = true,
+            _ => positional.push(arg),
+        }
+    }
+
+    let [address, nickname]: [String; 2] = positional.try_into().expect(USAGE);
+    let credential = match (std::env::var("CHAT_TOKEN"), std::env::var("CHAT_PASSWORD")) {
+        (Ok(token), _) => Some(Credential::Token(token)),
+        (_, Ok(password)) => Some(Credential::Password(password)),
+        _ => None,
+    };
+
+    if let Some(ca_path) = tls_ca {
+        let name = match tls_name {
+            Some(name) => name,
+            None => host_name(&address).to_string(),
+        };
+        options.tls = Some((tls::connector(&ca_path)?, name));
+    }
+
+    let (event_sender, mut events) = mpsc::unbounded();
+    let (sender, mut commands) = mpsc::unbounded();
+    let session = Session::new(
+        address,
+        options,
+        Arc::new(nickname.clone()),
+        credential,
+        event_sender.clone(),
+    );
+
+    if plain || !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        plain::print_commands();
+        runtime::spawn_blocking(move || plain::read_commands(sender));
+
+        return runtime::block_on(async {
+            let result = session
+                .keep_connected(&mut commands)
+                .race(plain::print_events(&mut events))
+                .await;
+
+            // Print whatever the session had to say before it finished.
+            while let Ok(event) = events.try_recv() {
+                plain::print_event(event);
+            }
+
+            result
+        });
+    }
+
+    let mut app = App::new(&nickname);
+    runtime::block_on(async {
+        let connection = futures::FutureExt::fuse(session.keep_connected(&mut commands));
+        let ui = futures::FutureExt::fuse(terminal::run(&mut app, &mut events, sender));
+        futures::pin_mut!(connection, ui);
+
+        futures::select! {
+            result = ui => {
+                let _ = runtime::timeout(QUIT_GRACE, connection).await;
+                result
+            }
+            result = connection => {
+                // Leave the screen up until the user has seen why.
+                let reason = match &result {
+                    Ok(()) => "Connection closed".to_string(),
+                    Err(error) => error.to_string(),
+                };
+                let status = format!("{reason}; press Control-C to exit");
+                let _ = event_sender.unbounded_send(Event::Status(status));
+
+                ui.await?;
+                result
+            }
+        }
+    })
+}
+
+/// Return the host part of an 'ADDRESS:PORT' string, without the brackets
+/// around an IPv6 address.
+fn host_name(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => address,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
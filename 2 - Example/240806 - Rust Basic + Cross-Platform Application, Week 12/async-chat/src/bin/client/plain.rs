@@ -0,0 +1,221 @@
+//! The line-at-a-time front end, for when the client isn't run on a
+//! terminal: commands are read from the standard input, and everything the
+//! server says is printed to the standard output.
+
+use async_chat::utils::ChatResult;
+use async_chat::{Attachment, FromServer, PostedMessage, Reactions};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use std::sync::Arc;
+
+use crate::command::{self, Command, COMMANDS};
+use crate::session::Event;
+
+pub fn print_commands() {
+    println!("Commands:");
+    for command in COMMANDS {
+        println!("{command}");
+    }
+    println!("Type Control-D (on Unix) or Control-Z (Windows) to close the connection.");
+}
+
+/// Parse commands from the standard input and pass them to 'commands',
+/// which outlives any one connection to the server. Reading the standard
+/// input blocks, so this should run on a thread of its own.
+pub fn read_commands(commands: UnboundedSender<Command>) -> ChatResult<()> {
+    for command_result in std::io::stdin().lines() {
+        let command = command_result?;
+        match command::parse_line(&command) {
+            Some(parsed) => {
+                if commands.unbounded_send(parsed).is_err() {
+                    break;
+                }
+            }
+            None => eprintln!("Unrecognized command: {:?}", command),
+        }
+    }
+
+    Ok(())
+}
+
+/// Print events as they arrive. This only returns once every sender is gone.
+pub async fn print_events(events: &mut UnboundedReceiver<Event>) -> ChatResult<()> {
+    while let Some(event) = events.next().await {
+        print_event(event);
+    }
+
+    Ok(())
+}
+
+pub fn print_event(event: Event) {
+    let packet = match event {
+        Event::Packet(packet) => packet,
+        Event::Status(message) => {
+            println!("{message}");
+            return;
+        }
+        Event::Missed { group_name, count } => {
+            println!("missed {count} messages in {group_name}");
+            return;
+        }
+    };
+
+    match packet {
+        FromServer::Hello {
+            version,
+            capabilities,
+        } => {
+            println!(
+                "using protocol version {version} with capabilities: {}",
+                capabilities.join(", ")
+            );
+        }
+        FromServer::LoggedIn { nickname } => {
+            println!("logged in as {nickname}");
+        }
+        FromServer::Message {
+            group_name,
+            sender,
+            message,
+            timestamp,
+            seq,
+            attachment,
+            edited,
+            deleted,
+            reactions,
+        } => {
+            let posted = PostedMessage {
+                sender,
+                message,
+                timestamp,
+                seq,
+                attachment,
+                edited,
+                deleted,
+                reactions,
+            };
+            let text = message_text(&posted);
+            println!("#{seq} {} posted to {group_name}: {text}", posted.sender);
+        }
+        FromServer::DirectMessage { from, message } => {
+            println!("{from} (privately): {message}");
+        }
+        FromServer::HistoryEnd { group_name } => {
+            println!("--- end of history for {group_name} ---");
+        }
+        FromServer::History {
+            group_name,
+            messages,
+        } => {
+            println!("history of {group_name}:");
+            for posted in messages {
+                println!(
+                    "  [{}] #{} {}: {}",
+                    posted.timestamp,
+                    posted.seq,
+                    posted.sender,
+                    message_text(&posted)
+                );
+            }
+        }
+        FromServer::Groups { group_names } => {
+            println!("groups: {}", join_names(&group_names));
+        }
+        FromServer::Members {
+            group_name,
+            nicknames,
+        } => {
+            println!("members of {group_name}: {}", join_names(&nicknames));
+        }
+        FromServer::Invited { group_name, by } => {
+            println!("{by} invited you to join {group_name}");
+        }
+        FromServer::Removed {
+            group_name,
+            by,
+            banned,
+        } => {
+            let action = if banned { "banned" } else { "removed" };
+            println!("{by} {action} you from {group_name}");
+        }
+        FromServer::Edited {
+            group_name,
+            seq,
+            message,
+        } => {
+            println!("#{seq} in {group_name} now reads: {message}");
+        }
+        FromServer::Deleted {
+            group_name,
+            seq,
+            by,
+        } => {
+            println!("{by} deleted #{seq} in {group_name}");
+        }
+        FromServer::Reacted {
+            group_name,
+            seq,
+            nickname,
+            reaction,
+            added,
+        } => {
+            let action = if added { "reacted" } else { "took back" };
+            println!("{nickname} {action} {reaction} to #{seq} in {group_name}");
+        }
+        FromServer::Pong | FromServer::Ack { .. } => {}
+        // The session collects downloads, and reports when they're saved.
+        FromServer::Offer { .. } | FromServer::Chunk { .. } | FromServer::Complete { .. } => {}
+        FromServer::Rejected { reason, .. } => {
+            println!("post not delivered: {reason}");
+        }
+        FromServer::Error(message) => {
+            println!("error from server: {message}");
+        }
+    }
+}
+
+/// Return what 'posted' says, followed by notes on its attachment, whether
+/// it was edited, and its reactions.
+pub fn message_text(posted: &PostedMessage) -> String {
+    if posted.deleted {
+        return "(deleted)".to_string();
+    }
+
+    let edited = if posted.edited { " (edited)" } else { "" };
+    format!(
+        "{}{}{edited}{}",
+        posted.message,
+        attachment_note(posted.attachment.as_ref()),
+        reaction_note(&posted.reactions)
+    )
+}
+
+/// List a message's reactions, and who gave each.
+pub fn reaction_note(reactions: &Reactions) -> String {
+    if reactions.is_empty() {
+        return String::new();
+    }
+
+    let reactions: Vec<String> = reactions
+        .iter()
+        .map(|(reaction, nicknames)| format!("{reaction} {}", join_names(nicknames)))
+        .collect();
+    format!(" [{}]", reactions.join("; "))
+}
+
+/// Describe a message's attachment, if it has one, with the id to download
+/// it by.
+pub fn attachment_note(attachment: Option<&Attachment>) -> String {
+    match attachment {
+        Some(attachment) => format!(
+            " [attached {} ({} bytes), id {}]",
+            attachment.file_name, attachment.size, attachment.id
+        ),
+        None => String::new(),
+    }
+}
+
+pub fn join_names(names: &[Arc<String>]) -> String {
+    let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+    names.join(", ")
+}
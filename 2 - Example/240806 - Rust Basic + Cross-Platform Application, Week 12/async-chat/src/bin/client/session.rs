@@ -0,0 +1,358 @@
+//! The connection to the server.
+//!
+//! A 'Session' connects, logs in and carries out the user's requests, and
+//! keeps reconnecting if the connection is lost. Whatever it hears from the
+//! server is passed on as 'Event's for the front end to show.
+//!
+//! The session also moves files: it reads and uploads those the user
+//! attaches, and saves those they download to the current directory.
+
+use async_chat::attachment::Download;
+use async_chat::client::{ChatClient, ChatSender, ClientOptions, SequenceTracker};
+use async_chat::codec::PacketStream;
+use async_chat::protocol;
+use async_chat::runtime;
+use async_chat::utils::ChatResult;
+use async_chat::{Credential, FromClient, FromServer};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use futures_lite::FutureExt as _;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::command::Command;
+
+/// How long to wait before trying to reconnect. Each failed attempt doubles
+/// the wait, up to 'MAX_BACKOFF'.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// When the server supports heartbeats, how long to let the connection sit
+/// idle before sending a 'Ping', and how long to wait to hear anything from
+/// the server before giving it up for dead.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const SERVER_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Something for the front end to show the user.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A packet from the server.
+    Packet(FromServer),
+    /// A change in the state of the connection itself.
+    Status(String),
+    /// 'count' messages posted to 'group_name' never arrived.
+    Missed { group_name: Arc<String>, count: u64 },
+}
+
+/// Everything needed to connect and log in, as often as it takes.
+pub struct Session {
+    address: String,
+    options: ClientOptions,
+    nickname: Arc<String>,
+    credential: Option<Credential>,
+    events: UnboundedSender<Event>,
+    /// The groups this user belongs to, to rejoin after reconnecting.
+    joined: RefCell<BTreeSet<Arc<String>>>,
+    /// Where each of those groups' messages are up to.
+    sequences: RefCell<SequenceTracker>,
+    /// Attachments on their way from the server, by id.
+    downloads: RefCell<HashMap<String, Download>>,
+    /// Whether the current connection has logged in.
+    logged_in: Cell<bool>,
+    /// Whether the current connection negotiated the 'heartbeat' capability.
+    heartbeat: Cell<bool>,
+}
+
+/// Why a connection ended without an error.
+enum Ended {
+    /// The user has no more commands.
+    Quit,
+    /// The server closed the connection.
+    Disconnected,
+}
+
+impl Session {
+    pub fn new(
+        address: String,
+        options: ClientOptions,
+        nickname: Arc<String>,
+        credential: Option<Credential>,
+        events: UnboundedSender<Event>,
+    ) -> Session {
+        Session {
+            address,
+            options,
+            nickname,
+            credential,
+            events,
+            joined: RefCell::new(BTreeSet::new()),
+            sequences: RefCell::new(SequenceTracker::new()),
+            downloads: RefCell::new(HashMap::new()),
+            logged_in: Cell::new(false),
+            heartbeat: Cell::new(false),
+        }
+    }
+
+    /// Carry out 'commands' until they run out, reconnecting whenever the
+    /// connection is lost. Give up only if the first connection fails before
+    /// logging in, since then there's no reason to think a retry will do
+    /// any better.
+    pub async fn keep_connected(
+        &self,
+        commands: &mut UnboundedReceiver<Command>,
+    ) -> ChatResult<()> {
+        let mut backoff = MIN_BACKOFF;
+        let mut ever_logged_in = false;
+
+        loop {
+            let result = self.run(commands).await;
+
+            if self.logged_in.get() {
+                ever_logged_in = true;
+                backoff = MIN_BACKOFF;
+            }
+
+            match result {
+                Ok(Ended::Quit) => return Ok(()),
+                Ok(Ended::Disconnected) if !ever_logged_in => {
+                    return Err("The server closed the connection".into());
+                }
+                Ok(Ended::Disconnected) => self.status("disconnected from server".to_string()),
+                // Only reconnect to a server we've managed to use before.
+                Err(error) if !ever_logged_in => return Err(error),
+                Err(error) => self.status(format!("connection lost: {error}")),
+            }
+
+            self.status(format!("reconnecting in {} seconds", backoff.as_secs()));
+            runtime::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Connect, log in and rejoin the user's groups, then carry out commands
+    /// until either side closes the connection.
+    async fn run(&self, commands: &mut UnboundedReceiver<Command>) -> ChatResult<Ended> {
+        self.logged_in.set(false);
+
+        let mut client = ChatClient::connect_with(&self.address, &self.options).await?;
+        let agreement = client.agreement().clone();
+        self.heartbeat.set(agreement.supports(protocol::HEARTBEAT));
+        self.packet(FromServer::Hello {
+            version: agreement.version,
+            capabilities: agreement.capabilities,
+        });
+
+        client
+            .login(&self.nickname, self.credential.clone())
+            .await?;
+        self.logged_in.set(true);
+        self.packet(FromServer::LoggedIn {
+            nickname: self.nickname.clone(),
+        });
+
+        let joined: Vec<_> = self.joined.borrow().iter().cloned().collect();
+        for group_name in joined {
+            client.send(&FromClient::Join { group_name }).await?;
+        }
+
+        let (sender, replies) = client.split();
+        let to_server = self.send_commands(sender, commands);
+        let from_server = async {
+            self.handle_replies(replies).await?;
+            Ok(Ended::Disconnected)
+        };
+
+        from_server.race(to_server).await
+    }
+
+    async fn send_commands(
+        &self,
+        mut to_server: ChatSender,
+        commands: &mut UnboundedReceiver<Command>,
+    ) -> ChatResult<Ended> {
+        loop {
+            let next = commands.next();
+            let command = if self.heartbeat.get() {
+                match runtime::timeout(PING_INTERVAL, next).await {
+                    Ok(command) => command,
+                    Err(_) => Some(Command::Request(FromClient::Ping)),
+                }
+            } else {
+                next.await
+            };
+            let request = match command {
+                Some(Command::Request(request)) => request,
+                Some(Command::Attach {
+                    group_name,
+                    path,
+                    message,
+                }) => {
+                    self.attach(&mut to_server, &group_name, &path, &message)
+                        .await?;
+                    continue;
+                }
+                None => break,
+            };
+
+            match request {
+                FromClient::Leave { ref group_name } => {
+                    self.joined.borrow_mut().remove(group_name);
+                    self.sequences.borrow_mut().forget(group_name);
+                    to_server.send(&request).await?;
+                }
+                // Number each post, so the server acknowledges it.
+                FromClient::Post {
+                    group_name,
+                    message,
+                    ..
+                } => {
+                    to_server.post(&group_name, &message).await?;
+                }
+                request => to_server.send(&request).await?,
+            }
+        }
+
+        to_server.close().await?;
+
+        Ok(Ended::Quit)
+    }
+
+    /// Upload the file at 'path' to 'group_name'. A file that can't be read
+    /// is the user's problem, not the connection's.
+    async fn attach(
+        &self,
+        to_server: &mut ChatSender,
+        group_name: &str,
+        path: &Path,
+        message: &str,
+    ) -> ChatResult<()> {
+        let reading = path.to_owned();
+        let data = match runtime::spawn_blocking(move || std::fs::read(reading)).await {
+            Ok(data) => data,
+            Err(error) => {
+                self.status(format!("could not read {}: {error}", path.display()));
+                return Ok(());
+            }
+        };
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => {
+                self.status(format!("{} is not a file", path.display()));
+                return Ok(());
+            }
+        };
+
+        to_server
+            .upload(group_name, &file_name, message, &data)
+            .await?;
+        self.status(format!("uploaded {file_name} to {group_name}"));
+        Ok(())
+    }
+
+    async fn handle_replies(&self, mut replies: PacketStream<FromServer>) -> ChatResult<()> {
+        loop {
+            let next = replies.next();
+            let reply = if self.heartbeat.get() {
+                runtime::timeout(SERVER_TIMEOUT, next)
+                    .await
+                    .map_err(|_| "The server stopped responding")?
+            } else {
+                next.await
+            };
+            let reply = match reply {
+                Some(reply) => reply?,
+                None => break,
+            };
+
+            match &reply {
+                FromServer::HistoryEnd { group_name } => {
+                    // This ends the replay sent to a new member, so it means
+                    // a join succeeded.
+                    self.joined.borrow_mut().insert(group_name.clone());
+                }
+                FromServer::Message {
+                    group_name, seq, ..
+                } => {
+                    if let Some(count) = self.sequences.borrow_mut().observe(group_name, *seq) {
+                        let group_name = group_name.clone();
+                        let _ = self
+                            .events
+                            .unbounded_send(Event::Missed { group_name, count });
+                    }
+                }
+                FromServer::Removed { group_name, .. } => {
+                    self.joined.borrow_mut().remove(group_name);
+                    self.sequences.borrow_mut().forget(group_name);
+                }
+                // Heartbeats are the session's business alone, and posts
+                // that went through need no comment.
+                FromServer::Pong | FromServer::Ack { .. } => continue,
+                FromServer::Offer { attachment } => {
+                    let download = Download::new(attachment.clone());
+                    self.downloads
+                        .borrow_mut()
+                        .insert(attachment.id.clone(), download);
+                    continue;
+                }
+                FromServer::Chunk { id, data } => {
+                    let mut downloads = self.downloads.borrow_mut();
+                    if let Some(download) = downloads.get_mut(id) {
+                        if let Err(message) = download.push(data) {
+                            downloads.remove(id);
+                            self.status(message);
+                        }
+                    }
+                    continue;
+                }
+                FromServer::Complete { id } => {
+                    if let Some(download) = self.downloads.borrow_mut().remove(id) {
+                        match save_download(download) {
+                            Ok(path) => self.status(format!("saved {}", path.display())),
+                            Err(message) => self.status(message),
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            self.packet(reply);
+        }
+
+        Ok(())
+    }
+
+    fn packet(&self, packet: FromServer) {
+        let _ = self.events.unbounded_send(Event::Packet(packet));
+    }
+
+    fn status(&self, message: String) {
+        let _ = self.events.unbounded_send(Event::Status(message));
+    }
+}
+
+/// Save a finished download in the current directory, under the file name it
+/// was shared with, but never over an existing file. Return where it went.
+fn save_download(download: Download) -> Result<PathBuf, String> {
+    // Keep only the last component, so a file name can't point elsewhere.
+    let path = match Path::new(&download.attachment.file_name).file_name() {
+        Some(file_name) => PathBuf::from(file_name),
+        None => PathBuf::from(&download.attachment.id),
+    };
+    let data = download.finish()?;
+
+    let describe = |error: std::io::Error| format!("could not save {}: {error}", path.display());
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(describe)?;
+    file.write_all(&data).map_err(describe)?;
+
+    Ok(path)
+}
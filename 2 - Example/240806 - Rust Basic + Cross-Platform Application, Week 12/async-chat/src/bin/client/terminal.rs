@@ -0,0 +1,182 @@
+//! Running 'App' full-screen: drawing it, and feeding it keys and events.
+
+use async_chat::utils::ChatResult;
+use crossterm::event::{Event as TerminalEvent, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::future::Either;
+use futures::prelude::*;
+use futures_lite::FutureExt as _;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line as TextLine;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::command::Command;
+use crate::session::Event;
+use crate::ui::{Action, App, Line};
+
+/// The width of the list of groups down the left side.
+const GROUP_LIST_WIDTH: u16 = 20;
+
+/// How many lines Page Up and Page Down scroll by.
+const PAGE: usize = 10;
+
+/// Take over the terminal and run 'app' until the user quits, sending their
+/// requests to 'commands'.
+pub async fn run(
+    app: &mut App,
+    events: &mut UnboundedReceiver<Event>,
+    commands: UnboundedSender<Command>,
+) -> ChatResult<()> {
+    let mut terminal = ratatui::init();
+    let result = interact(&mut terminal, app, events, commands).await;
+    ratatui::restore();
+
+    result
+}
+
+async fn interact(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    events: &mut UnboundedReceiver<Event>,
+    commands: UnboundedSender<Command>,
+) -> ChatResult<()> {
+    let mut keys = EventStream::new();
+
+    loop {
+        terminal.draw(|frame| draw(frame, app))?;
+
+        let key = async { Either::Left(keys.next().await) };
+        let event = async { Either::Right(events.next().await) };
+
+        match key.race(event).await {
+            Either::Left(Some(key)) => {
+                let keep_going = match handle_key(app, key?) {
+                    Some(Action::Send(command)) => commands.unbounded_send(command).is_ok(),
+                    Some(Action::Quit) => false,
+                    None => true,
+                };
+                if !keep_going {
+                    return Ok(());
+                }
+            }
+            Either::Right(Some(event)) => {
+                app.apply(event);
+                // Take in a burst of messages before drawing again.
+                while let Ok(event) = events.try_recv() {
+                    app.apply(event);
+                }
+            }
+            Either::Left(None) | Either::Right(None) => return Ok(()),
+        }
+    }
+}
+
+fn handle_key(app: &mut App, event: TerminalEvent) -> Option<Action> {
+    let key = match event {
+        TerminalEvent::Key(key) if key.kind != KeyEventKind::Release => key,
+        _ => return None,
+    };
+
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        match key.code {
+            KeyCode::Char('c') => return Some(Action::Quit),
+            KeyCode::Char('n') => app.select_relative(1),
+            KeyCode::Char('p') => app.select_relative(-1),
+            KeyCode::Char('a') => app.move_cursor_home(),
+            KeyCode::Char('e') => app.move_cursor_end(),
+            _ => {}
+        }
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Enter => return app.submit(),
+        KeyCode::Tab => app.complete(),
+        KeyCode::Backspace => app.backspace(),
+        KeyCode::Delete => app.delete(),
+        KeyCode::Left => app.move_cursor(-1),
+        KeyCode::Right => app.move_cursor(1),
+        KeyCode::Home => app.move_cursor_home(),
+        KeyCode::End => app.move_cursor_end(),
+        KeyCode::Up => app.history_previous(),
+        KeyCode::Down => app.history_next(),
+        KeyCode::PageUp => app.scroll_up(PAGE),
+        KeyCode::PageDown => app.scroll_down(PAGE),
+        KeyCode::Char(c) => app.insert(c.encode_utf8(&mut [0; 4])),
+        _ => {}
+    }
+
+    None
+}
+
+fn draw(frame: &mut Frame, app: &App) {
+    let [main, status, input] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [group_list, messages] =
+        Layout::horizontal([Constraint::Length(GROUP_LIST_WIDTH), Constraint::Min(10)]).areas(main);
+
+    let items: Vec<ListItem> = app
+        .panes()
+        .iter()
+        .map(|pane| match pane.unread {
+            0 => ListItem::new(pane.title().to_string()),
+            unread => ListItem::new(format!("{} ({unread})", pane.title()))
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title("Groups"))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut list_state = ListState::default().with_selected(Some(app.selected()));
+    frame.render_stateful_widget(list, group_list, &mut list_state);
+
+    let pane = &app.panes()[app.selected()];
+    let block = Block::bordered().title(pane.title());
+    let inner = block.inner(messages);
+
+    // Wrap the lines ourselves, so we know which rows fit at the bottom.
+    let width = inner.width.max(1) as usize;
+    let shown = pane.lines.len() - pane.scroll;
+    let mut rows: Vec<TextLine> = Vec::new();
+    for line in pane.lines.iter().take(shown) {
+        let style = match line {
+            Line::Message(_) => Style::new(),
+            Line::Notice(_) => Style::new().fg(Color::DarkGray),
+            Line::Error(_) => Style::new().fg(Color::Red),
+        };
+        let chars: Vec<char> = line.text().chars().collect();
+        for chunk in chars.chunks(width) {
+            rows.push(TextLine::styled(chunk.iter().collect::<String>(), style));
+        }
+    }
+    let visible = rows.split_off(rows.len().saturating_sub(inner.height as usize));
+    frame.render_widget(Paragraph::new(visible).block(block), messages);
+
+    let status_text = match pane.scroll {
+        0 => app.status().to_string(),
+        scroll => format!("{} [scrolled back {scroll} lines]", app.status()),
+    };
+    frame.render_widget(
+        Paragraph::new(status_text).style(Style::new().add_modifier(Modifier::REVERSED)),
+        status,
+    );
+
+    // Scroll the input line sideways to keep the cursor in view.
+    let prompt = "> ";
+    let room = (input.width as usize)
+        .saturating_sub(prompt.len() + 1)
+        .max(1);
+    let skip = app.cursor().saturating_sub(room);
+    let visible_input: String = app.input().chars().skip(skip).collect();
+    frame.render_widget(Paragraph::new(format!("{prompt}{visible_input}")), input);
+    frame.set_cursor_position(Position::new(
+        input.x + (prompt.len() + app.cursor() - skip) as u16,
+        input.y,
+    ));
+}
@@ -0,0 +1,797 @@
+//! The state of the full-screen interface: a pane for each group the user
+//! belongs to, plus one for the server itself, and the line being typed.
+//!
+//! Text typed at the input line is posted to the selected group, unless it
+//! starts with '/', in which case it is a command. Commands about a group
+//! act on the selected one if the group is left out. Drawing all this and
+//! reading keys is up to 'terminal'.
+
+use async_chat::{FromClient, FromServer, PostedMessage};
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use crate::command::{self, Command, COMMANDS};
+use crate::plain::{join_names, message_text};
+use crate::session::Event;
+
+/// How many lines each pane keeps for scrolling back through.
+const MAX_LINES: usize = 1000;
+
+/// How many lines of input the Up key can recall.
+const MAX_INPUT_HISTORY: usize = 100;
+
+/// Commands the interface handles itself, rather than sending to the server.
+const LOCAL_COMMANDS: &[&str] = &["switch GROUP", "help", "quit"];
+
+/// Commands whose first argument is a group they act on, which may be left
+/// out to mean the selected group.
+const GROUP_COMMANDS: &[&str] = &[
+    "leave", "post", "edit", "delete", "react", "members", "history", "invite", "kick", "ban",
+    "mod", "unmod", "attach",
+];
+
+/// What the front end should do after a key press.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Send(Command),
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Message(PostedMessage),
+    Notice(String),
+    Error(String),
+}
+
+pub struct Pane {
+    /// The group shown, or 'None' for the server's own pane.
+    pub group_name: Option<Arc<String>>,
+    pub lines: VecDeque<Line>,
+    /// How many messages arrived while another pane was selected.
+    pub unread: usize,
+    /// How many lines up from the bottom the pane is scrolled.
+    pub scroll: usize,
+    /// The timestamp of the newest message shown. After a reconnection, the
+    /// server replays a group's recent history, which we've already seen.
+    latest: u64,
+}
+
+pub struct App {
+    /// The server's pane comes first, followed by the groups in the order
+    /// they were joined.
+    panes: Vec<Pane>,
+    selected: usize,
+    /// A group the user asked to join, to select once the join succeeds.
+    switch_to: Option<Arc<String>>,
+    /// Every group we've heard of, for tab completion.
+    known_groups: BTreeSet<Arc<String>>,
+    status: String,
+    input: String,
+    /// The cursor's position in 'input', in characters.
+    cursor: usize,
+    input_history: Vec<String>,
+    /// The entry of 'input_history' being shown, if the user has gone back.
+    history_index: Option<usize>,
+    /// What the user was typing before going back through the history.
+    draft: String,
+}
+
+impl Pane {
+    fn new(group_name: Option<Arc<String>>) -> Pane {
+        Pane {
+            group_name,
+            lines: VecDeque::new(),
+            unread: 0,
+            scroll: 0,
+            latest: 0,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        match &self.group_name {
+            Some(group_name) => group_name,
+            None => "server",
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        self.lines.push_back(line);
+        if self.lines.len() > MAX_LINES {
+            self.lines.pop_front();
+        }
+
+        // Keep a scrolled-back view still as new lines arrive.
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len() - 1);
+        }
+    }
+
+    /// Return the message numbered 'seq', if it is still shown.
+    fn message_mut(&mut self, seq: u64) -> Option<&mut PostedMessage> {
+        self.lines.iter_mut().rev().find_map(|line| match line {
+            Line::Message(posted) if posted.seq == seq && seq != 0 => Some(posted),
+            _ => None,
+        })
+    }
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        match self {
+            Line::Message(posted) => format!(
+                "{} #{} {}: {}",
+                clock(posted.timestamp),
+                posted.seq,
+                posted.sender,
+                message_text(posted)
+            ),
+            Line::Notice(text) => text.clone(),
+            Line::Error(text) => format!("error: {text}"),
+        }
+    }
+}
+
+impl App {
+    pub fn new(nickname: &str) -> App {
+        App {
+            panes: vec![Pane::new(None)],
+            selected: 0,
+            switch_to: None,
+            known_groups: BTreeSet::new(),
+            status: format!("Connecting as {nickname}"),
+            input: String::new(),
+            cursor: 0,
+            input_history: Vec::new(),
+            history_index: None,
+            draft: String::new(),
+        }
+    }
+
+    pub fn panes(&self) -> &[Pane] {
+        &self.panes
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    fn current_group(&self) -> Option<Arc<String>> {
+        self.panes[self.selected].group_name.clone()
+    }
+
+    /// Show what the session has heard from the server.
+    pub fn apply(&mut self, event: Event) {
+        let packet = match event {
+            Event::Packet(packet) => packet,
+            Event::Status(message) => {
+                self.status = message.clone();
+                self.show(Line::Notice(message));
+                return;
+            }
+            Event::Missed { group_name, count } => {
+                let index = self.pane_index(&group_name);
+                self.panes[index].push(Line::Error(format!("Missed {count} messages")));
+                return;
+            }
+        };
+
+        match packet {
+            FromServer::Hello {
+                version,
+                capabilities,
+            } => {
+                self.panes[0].push(Line::Notice(format!(
+                    "Using protocol version {version} with capabilities: {}",
+                    capabilities.join(", ")
+                )));
+            }
+            FromServer::LoggedIn { nickname } => {
+                self.status = format!("Logged in as {nickname}");
+                self.panes[0].push(Line::Notice(self.status.clone()));
+            }
+            FromServer::Message {
+                group_name,
+                sender,
+                message,
+                timestamp,
+                seq,
+                attachment,
+                edited,
+                deleted,
+                reactions,
+            } => self.receive(
+                group_name,
+                PostedMessage {
+                    sender,
+                    message,
+                    timestamp,
+                    seq,
+                    attachment,
+                    edited,
+                    deleted,
+                    reactions,
+                },
+            ),
+            FromServer::DirectMessage { from, message } => {
+                self.show(Line::Notice(format!("{from} (privately): {message}")));
+            }
+            FromServer::HistoryEnd { group_name } => {
+                let index = self.pane_index(&group_name);
+                if self.switch_to.as_ref() == Some(&group_name) {
+                    self.switch_to = None;
+                    self.select(index);
+                }
+            }
+            FromServer::History {
+                group_name,
+                messages,
+            } => {
+                self.show(Line::Notice(format!("History of {group_name}:")));
+                for posted in messages {
+                    self.show(Line::Message(posted));
+                }
+            }
+            FromServer::Groups { group_names } => {
+                self.show(Line::Notice(format!(
+                    "Groups: {}",
+                    join_names(&group_names)
+                )));
+                self.known_groups.extend(group_names);
+            }
+            FromServer::Members {
+                group_name,
+                nicknames,
+            } => {
+                let nicknames = join_names(&nicknames);
+                self.show(Line::Notice(format!(
+                    "Members of {group_name}: {nicknames}"
+                )));
+            }
+            FromServer::Invited { group_name, by } => {
+                self.show(Line::Notice(format!(
+                    "{by} invited you to join {group_name}"
+                )));
+                self.known_groups.insert(group_name);
+            }
+            FromServer::Removed {
+                group_name,
+                by,
+                banned,
+            } => {
+                self.remove_pane(&group_name);
+                let action = if banned { "banned" } else { "removed" };
+                self.show(Line::Notice(format!("{by} {action} you from {group_name}")));
+            }
+            FromServer::Edited {
+                group_name,
+                seq,
+                message,
+            } => self.change(&group_name, seq, |posted| {
+                posted.message = message;
+                posted.edited = true;
+            }),
+            FromServer::Deleted {
+                group_name, seq, ..
+            } => self.change(&group_name, seq, |posted| {
+                posted.message = Arc::new(String::new());
+                posted.attachment = None;
+                posted.reactions.clear();
+                posted.deleted = true;
+            }),
+            FromServer::Reacted {
+                group_name,
+                seq,
+                nickname,
+                reaction,
+                added,
+            } => self.change(&group_name, seq, |posted| {
+                let nicknames = posted.reactions.entry(reaction.clone()).or_default();
+                nicknames.retain(|n| *n != nickname);
+                if added {
+                    nicknames.push(nickname);
+                } else if nicknames.is_empty() {
+                    posted.reactions.remove(&reaction);
+                }
+            }),
+            FromServer::Pong | FromServer::Ack { .. } => {}
+            // The session collects downloads, and reports when they're saved.
+            FromServer::Offer { .. } | FromServer::Chunk { .. } | FromServer::Complete { .. } => {}
+            FromServer::Rejected { reason, .. } => {
+                self.show(Line::Error(format!("Post not delivered: {reason}")));
+            }
+            FromServer::Error(message) => self.show(Line::Error(message)),
+        }
+    }
+
+    /// Add a message to its group's pane. If it's already there, show it as
+    /// it is now, since it may have changed while we were away.
+    fn receive(&mut self, group_name: Arc<String>, posted: PostedMessage) {
+        let index = self.pane_index(&group_name);
+        let pane = &mut self.panes[index];
+
+        if posted.timestamp <= pane.latest {
+            if let Some(shown) = pane.message_mut(posted.seq) {
+                *shown = posted;
+            }
+            return;
+        }
+
+        pane.latest = posted.timestamp;
+        pane.push(Line::Message(posted));
+        if index != self.selected {
+            pane.unread += 1;
+        }
+    }
+
+    /// Apply 'change' to message 'seq' in the pane for 'group_name', if it's
+    /// shown there.
+    fn change(&mut self, group_name: &str, seq: u64, change: impl FnOnce(&mut PostedMessage)) {
+        let message = self
+            .find_pane(group_name)
+            .and_then(|index| self.panes[index].message_mut(seq));
+        if let Some(posted) = message {
+            change(posted);
+        }
+    }
+
+    /// Show 'line' in the selected pane.
+    fn show(&mut self, line: Line) {
+        self.panes[self.selected].push(line);
+    }
+
+    fn find_pane(&self, group_name: &str) -> Option<usize> {
+        self.panes
+            .iter()
+            .position(|pane| pane.group_name.as_deref().map(String::as_str) == Some(group_name))
+    }
+
+    /// Return the index of the pane for 'group_name', adding one if need be.
+    fn pane_index(&mut self, group_name: &Arc<String>) -> usize {
+        if let Some(index) = self.find_pane(group_name) {
+            return index;
+        }
+
+        self.known_groups.insert(group_name.clone());
+        self.panes.push(Pane::new(Some(group_name.clone())));
+        self.panes.len() - 1
+    }
+
+    fn remove_pane(&mut self, group_name: &str) {
+        let Some(index) = self.find_pane(group_name) else {
+            return;
+        };
+
+        self.panes.remove(index);
+        if self.selected >= index {
+            self.select(self.selected.saturating_sub(1).min(self.panes.len() - 1));
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index;
+        self.panes[index].unread = 0;
+    }
+
+    /// Select the pane 'offset' places after the selected one, wrapping
+    /// around at either end.
+    pub fn select_relative(&mut self, offset: isize) {
+        let count = self.panes.len() as isize;
+        self.select((self.selected as isize + offset).rem_euclid(count) as usize);
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = (pane.scroll + lines).min(pane.lines.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        let pane = &mut self.panes[self.selected];
+        pane.scroll = pane.scroll.saturating_sub(lines);
+    }
+
+    /// Return the byte offset in 'input' of the character at 'position'.
+    fn byte_offset(&self, position: usize) -> usize {
+        self.input
+            .char_indices()
+            .nth(position)
+            .map_or(self.input.len(), |(offset, _)| offset)
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        let offset = self.byte_offset(self.cursor);
+        self.input.insert_str(offset, text);
+        self.cursor += text.chars().count();
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.delete();
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.input.chars().count() {
+            let offset = self.byte_offset(self.cursor);
+            self.input.remove(offset);
+        }
+    }
+
+    pub fn move_cursor(&mut self, offset: isize) {
+        let length = self.input.chars().count() as isize;
+        self.cursor = (self.cursor as isize + offset).clamp(0, length) as usize;
+    }
+
+    pub fn move_cursor_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_cursor_end(&mut self) {
+        self.cursor = self.input.chars().count();
+    }
+
+    fn set_input(&mut self, text: String) {
+        self.input = text;
+        self.move_cursor_end();
+    }
+
+    /// Replace the input with the previous line from the history.
+    pub fn history_previous(&mut self) {
+        let index = match self.history_index {
+            None if self.input_history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.input);
+                self.input_history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+
+        self.history_index = Some(index);
+        self.set_input(self.input_history[index].clone());
+    }
+
+    /// Replace the input with the next line from the history, or what the
+    /// user was typing before they went back.
+    pub fn history_next(&mut self) {
+        match self.history_index {
+            None => {}
+            Some(index) if index + 1 < self.input_history.len() => {
+                self.history_index = Some(index + 1);
+                self.set_input(self.input_history[index + 1].clone());
+            }
+            Some(_) => {
+                self.history_index = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_input(draft);
+            }
+        }
+    }
+
+    /// Complete the word before the cursor as a command name, if it's the
+    /// first on the line and starts with '/', or otherwise as a group name.
+    /// If it could be completed several ways, complete as much as they have
+    /// in common, or list them if that's no help.
+    pub fn complete(&mut self) {
+        let before = &self.input[..self.byte_offset(self.cursor)];
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(offset, c)| offset + c.len_utf8());
+        let word = &before[start..];
+
+        let candidates: Vec<String> = if start == 0 && word.starts_with('/') {
+            COMMANDS
+                .iter()
+                .chain(LOCAL_COMMANDS)
+                .filter_map(|usage| usage.split_whitespace().next())
+                .map(|name| format!("/{name}"))
+                .filter(|name| name.starts_with(word))
+                .collect()
+        } else {
+            self.known_groups
+                .iter()
+                .filter(|name| name.starts_with(word))
+                .map(|name| name.to_string())
+                .collect()
+        };
+
+        let completion = match &candidates[..] {
+            [] => return,
+            [only] => format!("{} ", &only[word.len()..]),
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(first.as_str(), |common, candidate| {
+                    let length = common
+                        .char_indices()
+                        .zip(candidate.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(common.len().min(candidate.len()), |((offset, _), _)| offset);
+                    &common[..length]
+                });
+
+                if common.len() <= word.len() {
+                    self.show(Line::Notice(candidates.join("  ")));
+                    return;
+                }
+                common[word.len()..].to_string()
+            }
+        };
+
+        self.insert(&completion);
+    }
+
+    /// Act on the line the user has typed, and clear it.
+    pub fn submit(&mut self) -> Option<Action> {
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.history_index = None;
+
+        if line.trim().is_empty() {
+            return None;
+        }
+
+        if self.input_history.last() != Some(&line) {
+            self.input_history.push(line.clone());
+            if self.input_history.len() > MAX_INPUT_HISTORY {
+                self.input_history.remove(0);
+            }
+        }
+
+        match line.strip_prefix('/') {
+            // A doubled slash starts a message that begins with a slash.
+            Some(command) if !command.starts_with('/') => self.run_command(command),
+            Some(message) => self.post(message),
+            None => self.post(&line),
+        }
+    }
+
+    fn post(&mut self, message: &str) -> Option<Action> {
+        let Some(group_name) = self.current_group() else {
+            self.show(Line::Error(
+                "Select a group to post to, or type /help for commands".to_string(),
+            ));
+            return None;
+        };
+
+        Some(Action::Send(Command::Request(FromClient::Post {
+            group_name,
+            message: Arc::new(message.to_string()),
+            id: None,
+        })))
+    }
+
+    fn run_command(&mut self, line: &str) -> Option<Action> {
+        let (name, rest) = command::get_next_token(line)?;
+
+        match name {
+            "quit" => return Some(Action::Quit),
+            "help" => {
+                self.show_help();
+                return None;
+            }
+            "switch" => {
+                match self.find_pane(rest.trim()) {
+                    Some(index) => self.select(index),
+                    None => self.show(Line::Error(format!("Not a member of '{}'", rest.trim()))),
+                }
+                return None;
+            }
+            _ => {}
+        }
+
+        // Fill in the selected group if it seems to have been left out, but
+        // fall back to the command as typed if that doesn't parse.
+        let with_group = match (self.current_group(), command::get_next_token(rest)) {
+            (Some(group_name), first) if GROUP_COMMANDS.contains(&name) => {
+                let names_group = first.is_some_and(|(first, _)| {
+                    self.known_groups
+                        .iter()
+                        .any(|known| known.as_str() == first)
+                });
+                if names_group {
+                    None
+                } else {
+                    command::parse_line(&format!("{name} {group_name} {rest}"))
+                }
+            }
+            _ => None,
+        };
+
+        let Some(parsed) = with_group.or_else(|| command::parse_line(line)) else {
+            self.show(Line::Error(format!(
+                "Couldn't understand '/{line}'; type /help for commands"
+            )));
+            return None;
+        };
+
+        match &parsed {
+            Command::Request(
+                FromClient::Join { group_name } | FromClient::Create { group_name, .. },
+            ) => match self.find_pane(group_name) {
+                Some(index) => self.select(index),
+                None => self.switch_to = Some(group_name.clone()),
+            },
+            Command::Request(FromClient::Leave { group_name }) => self.remove_pane(group_name),
+            _ => {}
+        }
+
+        Some(Action::Send(parsed))
+    }
+
+    fn show_help(&mut self) {
+        self.show(Line::Notice(
+            "Type a message to post it to the selected group, or one of these commands:"
+                .to_string(),
+        ));
+        for usage in COMMANDS.iter().chain(LOCAL_COMMANDS) {
+            self.show(Line::Notice(format!("  /{usage}")));
+        }
+        for help in [
+            "Commands about a group act on the selected one if the group is left out.",
+            "Tab completes commands and group names, and Up and Down recall earlier lines.",
+            "Control-N and Control-P select the next and previous group, and",
+            "Page Up and Page Down scroll. Control-C quits.",
+        ] {
+            self.show(Line::Notice(help.to_string()));
+        }
+    }
+}
+
+/// Format a timestamp in milliseconds since the Unix epoch as a UTC time of
+/// day, 'HH:MM'.
+fn clock(timestamp: u64) -> String {
+    let minutes = timestamp / 60_000;
+    format!("{:02}:{:02}", minutes / 60 % 24, minutes % 60)
+}
+
+#[test]
+fn test_app() {
+    let name = |text: &str| Arc::new(text.to_string());
+    let message = |group_name: &str, text: &str, timestamp| {
+        Event::Packet(FromServer::Message {
+            group_name: name(group_name),
+            sender: name("jimb"),
+            message: name(text),
+            timestamp,
+            seq: timestamp,
+            attachment: None,
+            edited: false,
+            deleted: false,
+            reactions: Default::default(),
+        })
+    };
+    let submit = |app: &mut App, line: &str| {
+        app.set_input(line.to_string());
+        app.submit()
+    };
+
+    let mut app = App::new("gus");
+    assert_eq!(submit(&mut app, "hello?"), None);
+    assert!(matches!(app.panes()[0].lines.back(), Some(Line::Error(_))));
+
+    // Joining selects the group once the server confirms it.
+    assert_eq!(
+        submit(&mut app, "/join Dogs"),
+        Some(Action::Send(Command::Request(FromClient::Join {
+            group_name: name("Dogs")
+        })))
+    );
+    app.apply(message("Dogs", "woof", 1));
+    assert_eq!(app.selected(), 0);
+    assert_eq!(app.panes()[1].unread, 1);
+    app.apply(Event::Packet(FromServer::HistoryEnd {
+        group_name: name("Dogs"),
+    }));
+    assert_eq!(app.selected(), 1);
+    assert_eq!(app.panes()[1].unread, 0);
+
+    // Messages replayed after reconnecting aren't shown twice.
+    app.apply(message("Dogs", "woof", 1));
+    app.apply(message("Dogs", "arf", 2));
+    assert_eq!(app.panes()[1].lines.len(), 2);
+
+    // Changes to a message are shown where it is.
+    app.apply(Event::Packet(FromServer::Edited {
+        group_name: name("Dogs"),
+        seq: 2,
+        message: name("arf arf"),
+    }));
+    app.apply(Event::Packet(FromServer::Reacted {
+        group_name: name("Dogs"),
+        seq: 2,
+        nickname: name("gus"),
+        reaction: name("+1"),
+        added: true,
+    }));
+    assert_eq!(
+        app.panes()[1].lines[1].text(),
+        "00:00 #2 jimb: arf arf (edited) [+1 gus]"
+    );
+    app.apply(Event::Packet(FromServer::Deleted {
+        group_name: name("Dogs"),
+        seq: 1,
+        by: name("jimb"),
+    }));
+    assert_eq!(app.panes()[1].lines[0].text(), "00:00 #1 jimb: (deleted)");
+
+    // Plain text and commands act on the selected group.
+    assert_eq!(
+        submit(&mut app, "good dog"),
+        Some(Action::Send(Command::Request(FromClient::Post {
+            group_name: name("Dogs"),
+            message: name("good dog"),
+            id: None,
+        })))
+    );
+    assert_eq!(
+        submit(&mut app, "/react 2 +1"),
+        Some(Action::Send(Command::Request(FromClient::React {
+            group_name: name("Dogs"),
+            seq: 2,
+            reaction: name("+1"),
+        })))
+    );
+    assert_eq!(
+        submit(&mut app, "/kick jimb"),
+        Some(Action::Send(Command::Request(FromClient::Kick {
+            group_name: name("Dogs"),
+            nickname: name("jimb"),
+        })))
+    );
+    assert_eq!(
+        submit(&mut app, "/attach rex.jpg good dog"),
+        Some(Action::Send(Command::Attach {
+            group_name: name("Dogs"),
+            path: "rex.jpg".into(),
+            message: name("good dog"),
+        }))
+    );
+    app.apply(Event::Packet(FromServer::Groups {
+        group_names: vec![name("Cats"), name("Dogs")],
+    }));
+    assert_eq!(
+        submit(&mut app, "/members Cats"),
+        Some(Action::Send(Command::Request(FromClient::ListMembers {
+            group_name: name("Cats")
+        })))
+    );
+
+    // Tab completes group names, and Up recalls earlier lines.
+    app.set_input("/members C".to_string());
+    app.complete();
+    assert_eq!(app.input(), "/members Cats ");
+    app.history_previous();
+    assert_eq!(app.input(), "/members Cats");
+    app.history_previous();
+    assert_eq!(app.input(), "/attach rex.jpg good dog");
+    app.history_next();
+    app.history_next();
+    assert_eq!(app.input(), "/members Cats ");
+
+    // Words may be separated by any kind of space, however many bytes long.
+    for space in ['\u{a0}', '\u{3000}'] {
+        app.set_input(format!("/members{space}D"));
+        app.complete();
+        assert_eq!(app.input(), format!("/members{space}Dogs "));
+    }
+
+    // Leaving a group closes its pane.
+    submit(&mut app, "/leave");
+    assert_eq!(app.panes().len(), 1);
+    assert_eq!(app.selected(), 0);
+    assert_eq!(submit(&mut app, "/quit"), Some(Action::Quit));
+}
@@ -0,0 +1,227 @@
+//! User accounts, for servers that only let known users in.
+//!
+//! Accounts are read from a text file with one account per line: a nickname
+//! and its secret, separated by whitespace. Blank lines and lines starting
+//! with '#' are ignored. A secret is either a salted password hash,
+//!
+//! ```text
+//! jimb pbkdf2-sha256:ITERATIONS:SALT:HASH
+//! ```
+//!
+//! or the hash of a token handed to the user out of band,
+//!
+//! ```text
+//! newsbot token:HASH
+//! ```
+//!
+//! where salts and hashes are in hexadecimal. 'server hash-password NICKNAME'
+//! and 'server new-token NICKNAME' print lines in this format.
+
+use async_chat::utils::ChatResult;
+use async_chat::Credential;
+use ring::digest::{self, SHA256};
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+/// How many PBKDF2 iterations new password hashes use.
+const ITERATIONS: u32 = 100_000;
+
+const SALT_LENGTH: usize = 16;
+const TOKEN_LENGTH: usize = 32;
+
+/// The known users, and how each one proves who they are.
+pub struct Accounts(HashMap<String, Secret>);
+
+#[derive(Debug, PartialEq)]
+enum Secret {
+    Password {
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+    Token {
+        hash: Vec<u8>,
+    },
+}
+
+impl Accounts {
+    pub fn load(path: &Path) -> ChatResult<Accounts> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("Could not read users from {}: {error}", path.display()))?;
+
+        Accounts::parse(&text)
+            .map_err(|error| format!("In users file {}: {error}", path.display()).into())
+    }
+
+    fn parse(text: &str) -> Result<Accounts, String> {
+        let mut accounts = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let line_number = index + 1;
+            let (nickname, secret) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [nickname, secret] => (nickname, secret),
+                _ => return Err(format!("line {line_number}: expected 'NICKNAME SECRET'")),
+            };
+            let secret = parse_secret(secret)
+                .ok_or_else(|| format!("line {line_number}: malformed secret"))?;
+
+            if accounts.insert(nickname.to_string(), secret).is_some() {
+                return Err(format!("line {line_number}: duplicate user '{nickname}'"));
+            }
+        }
+
+        Ok(Accounts(accounts))
+    }
+
+    /// Return 'true' if 'credential' proves the client may use 'nickname'.
+    pub fn verify(&self, nickname: &str, credential: Option<&Credential>) -> bool {
+        match (self.0.get(nickname), credential) {
+            (
+                Some(Secret::Password {
+                    iterations,
+                    salt,
+                    hash,
+                }),
+                Some(Credential::Password(password)),
+            ) => pbkdf2::verify(
+                PBKDF2_HMAC_SHA256,
+                *iterations,
+                salt,
+                password.as_bytes(),
+                hash,
+            )
+            .is_ok(),
+            (Some(Secret::Token { hash }), Some(Credential::Token(token))) => {
+                constant_time_eq(digest::digest(&SHA256, token.as_bytes()).as_ref(), hash)
+            }
+            (_, credential) => {
+                // Take as long as checking a real password would, so the
+                // response time doesn't reveal which nicknames exist.
+                let attempt = match credential {
+                    Some(Credential::Password(text) | Credential::Token(text)) => text.as_bytes(),
+                    None => &[],
+                };
+                let _ = pbkdf2::verify(
+                    PBKDF2_HMAC_SHA256,
+                    NonZeroU32::new(ITERATIONS).unwrap(),
+                    &[0; SALT_LENGTH],
+                    attempt,
+                    &[0; digest::SHA256_OUTPUT_LEN],
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Return a users file line giving 'nickname' the password 'password'.
+pub fn password_line(nickname: &str, password: &str) -> ChatResult<String> {
+    let mut salt = [0; SALT_LENGTH];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| "Could not generate a salt")?;
+
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+
+    Ok(format!(
+        "{nickname} pbkdf2-sha256:{iterations}:{}:{}",
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Generate a new token, and return it along with a users file line that
+/// lets 'nickname' log in with it.
+pub fn new_token(nickname: &str) -> ChatResult<(String, String)> {
+    let mut token = [0; TOKEN_LENGTH];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| "Could not generate a token")?;
+
+    let token = to_hex(&token);
+    let hash = digest::digest(&SHA256, token.as_bytes());
+
+    Ok((token, format!("{nickname} token:{}", to_hex(hash.as_ref()))))
+}
+
+fn parse_secret(secret: &str) -> Option<Secret> {
+    let mut fields = secret.split(':');
+
+    let secret = match fields.next()? {
+        "pbkdf2-sha256" => Secret::Password {
+            iterations: fields.next()?.parse().ok()?,
+            salt: from_hex(fields.next()?)?,
+            hash: from_hex(fields.next()?)?,
+        },
+        "token" => Secret::Token {
+            hash: from_hex(fields.next()?)?,
+        },
+        _ => return None,
+    };
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    Some(secret)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Compare 'a' and 'b' in time that depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[test]
+fn test_accounts() {
+    let password = |text: &str| Credential::Password(text.to_string());
+    let token = |text: &str| Credential::Token(text.to_string());
+
+    let (bot_token, bot_line) = new_token("newsbot").unwrap();
+    let text = format!(
+        "# Who may connect\n\n{}\n{bot_line}\n",
+        password_line("jimb", "hunter2").unwrap()
+    );
+    let accounts = Accounts::parse(&text).unwrap();
+
+    assert!(accounts.verify("jimb", Some(&password("hunter2"))));
+    assert!(!accounts.verify("jimb", Some(&password("hunter3"))));
+    assert!(!accounts.verify("jimb", None));
+    assert!(accounts.verify("newsbot", Some(&token(&bot_token))));
+    assert!(!accounts.verify("newsbot", Some(&password(&bot_token))));
+    assert!(!accounts.verify("stranger", Some(&password("hunter2"))));
+
+    assert!(Accounts::parse("jimb\n").is_err());
+    assert!(Accounts::parse("jimb token:xyz\n").is_err());
+    assert!(Accounts::parse(&format!("{bot_line}\n{bot_line}\n")).is_err());
+}
@@ -0,0 +1,303 @@
+//! The admin interface: a small HTTP server for operators, enabled with
+//! '--admin ADDRESS'. It answers:
+//!
+//! - 'GET /metrics': the server's counters, in the Prometheus text format
+//! - 'GET /connections': every open connection, as JSON
+//! - 'GET /groups': every group, with its owner and members, as JSON
+//! - 'POST /groups/NAME/close': remove everyone from group NAME, and drop it
+//!
+//! Nothing is authenticated, so ADDRESS should be reachable by operators
+//! only, such as a loopback address.
+
+use async_chat::runtime::{self, TcpListener, TcpStream};
+use async_chat::utils::ChatResult;
+use async_chat::{protocol, FromServer};
+use futures::io::BufReader;
+use futures::prelude::*;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::logging::log;
+use crate::metrics::Gauges;
+use crate::state::ServerState;
+
+/// The longest request line and headers accepted, together.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Who clients are told removed them from a group closed here. Nicknames
+/// can't contain spaces, so this can't be mistaken for a user.
+const ADMINISTRATOR: &str = "server administrator";
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Response {
+                status: "200 OK",
+                content_type: "application/json",
+                body: body + "\n",
+            },
+            Err(error) => Response::text("500 Internal Server Error", format!("{error}\n")),
+        }
+    }
+}
+
+pub async fn serve_admin(listener: TcpListener, state: Arc<ServerState>) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _peer)) => socket,
+            Err(error) => {
+                log!(Warn, "admin_accept_failed", error = error);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        runtime::spawn(async move {
+            if let Err(error) = handle_request(socket, &state).await {
+                log!(Warn, "admin_request_failed", error = error);
+            }
+        });
+    }
+}
+
+async fn handle_request(socket: TcpStream, state: &ServerState) -> ChatResult<()> {
+    let (reader, mut writer) = socket.split();
+    let mut request = BufReader::new(reader.take(MAX_REQUEST_BYTES));
+
+    let mut request_line = String::new();
+    request.read_line(&mut request_line).await?;
+
+    // No request needs a body, or anything from the headers.
+    loop {
+        let mut header = String::new();
+        if request.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        [method, target, _version] => {
+            let path = target.split('?').next().unwrap_or(target);
+            respond(state, method, path)
+        }
+        _ => Response::text("400 Bad Request", "Malformed request\n".to_string()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(response.body.as_bytes()).await?;
+
+    Ok(())
+}
+
+fn respond(state: &ServerState, method: &str, path: &str) -> Response {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, &segments[..]) {
+        ("GET", ["metrics"]) => {
+            let gauges = Gauges {
+                connections: state.connections.len(),
+                users: state.users.len(),
+                groups: state.groups.len(),
+            };
+            Response {
+                status: "200 OK",
+                content_type: "text/plain; version=0.0.4",
+                body: state.metrics.render(&gauges),
+            }
+        }
+        ("GET", ["connections"]) => Response::json(&state.connections.list()),
+        ("GET", ["groups"]) => Response::json(&state.groups.summaries()),
+        ("POST", ["groups", name, "close"]) => match percent_decode(name) {
+            Some(name) => close_group(state, name),
+            None => Response::text("400 Bad Request", "Malformed group name\n".to_string()),
+        },
+        (_, ["metrics" | "connections" | "groups"] | ["groups", _, "close"]) => Response::text(
+            "405 Method Not Allowed",
+            format!("{method} is not allowed here\n"),
+        ),
+        _ => Response::text("404 Not Found", format!("Nothing at '{path}'\n")),
+    }
+}
+
+/// Remove every member of the group named 'name', telling each of them why,
+/// and drop the group.
+fn close_group(state: &ServerState, name: String) -> Response {
+    let Some(members) = state.groups.close(&name) else {
+        return Response::text("404 Not Found", format!("No group named '{name}'\n"));
+    };
+
+    let group_name = Arc::new(name);
+    for nickname in &members {
+        let Some(outbound) = state.users.get(nickname) else {
+            continue;
+        };
+
+        let packet = if outbound.supports(protocol::MODERATION) {
+            FromServer::Removed {
+                group_name: group_name.clone(),
+                by: Arc::new(ADMINISTRATOR.to_string()),
+                banned: false,
+            }
+        } else {
+            FromServer::Error(format!(
+                "Group '{group_name}' was closed by the {ADMINISTRATOR}"
+            ))
+        };
+        let _ = outbound.send(packet);
+    }
+
+    log!(
+        Info,
+        "group_closed",
+        group = group_name,
+        members = members.len()
+    );
+    Response::json(&serde_json::json!({
+        "group": group_name,
+        "removed": members,
+    }))
+}
+
+/// Decode the '%XX' escapes in a URL path segment.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[test]
+fn test_admin_interface() {
+    use crate::config::ServerConfig;
+    use crate::connection::serve;
+    use crate::harness::name;
+    use async_chat::codec::{Codec, PacketStream};
+    use async_chat::utils;
+    use async_chat::FromClient;
+    use std::net::SocketAddr;
+
+    let config = ServerConfig::from_args(vec!["127.0.0.1:0".to_string()]).unwrap();
+    let state = Arc::new(ServerState::new(&config).unwrap());
+
+    async fn http(address: SocketAddr, method: &str, path: &str) -> (String, String) {
+        let mut socket = TcpStream::connect(&address.to_string()).await.unwrap();
+        let request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        socket.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    runtime::block_on(async {
+        let chat_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let chat_address = chat_listener.local_addr().unwrap();
+        let admin = admin_listener.local_addr().unwrap();
+
+        let chat_state = state.clone();
+        runtime::spawn(async move {
+            let (socket, peer) = chat_listener.accept().await.unwrap();
+            serve(socket, peer, chat_state).await
+        });
+        runtime::spawn(serve_admin(admin_listener, state.clone()));
+
+        let client = TcpStream::connect(&chat_address.to_string()).await.unwrap();
+        let (reader, mut client) = client.split();
+        let requests = [
+            FromClient::Hello {
+                version: protocol::VERSION,
+                capabilities: vec![protocol::MODERATION.to_string()],
+            },
+            FromClient::Login {
+                nickname: name("jimb"),
+                credential: None,
+            },
+            FromClient::Join {
+                group_name: name("Dogs"),
+            },
+            FromClient::Post {
+                group_name: name("Dogs"),
+                message: name("woof"),
+                id: None,
+            },
+        ];
+        for request in &requests {
+            utils::send_as_json(&mut client, request).await.unwrap();
+        }
+
+        let mut replies: PacketStream<FromServer> = Codec::Json.receive(BufReader::new(reader));
+        loop {
+            let reply = replies.next().await.unwrap().unwrap();
+            if matches!(reply, FromServer::Message { .. }) {
+                break;
+            }
+        }
+
+        let (status, metrics) = http(admin, "GET", "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(metrics.contains("\nchat_messages_posted_total 1\n"));
+        assert!(metrics.contains("\nchat_logins_total 1\n"));
+        assert!(metrics.contains("\nchat_connections 1\n"));
+        assert!(metrics.contains("\nchat_groups 1\n"));
+
+        let (_, connections) = http(admin, "GET", "/connections").await;
+        let connections: serde_json::Value = serde_json::from_str(&connections).unwrap();
+        assert_eq!(connections[0]["nickname"], "jimb");
+        assert_eq!(connections[0]["transport"], "tcp");
+
+        // Closing a group tells its members, and leaves no trace of it.
+        let (status, _) = http(admin, "POST", "/groups/Dogs/close").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            replies.next().await.unwrap().unwrap(),
+            FromServer::Removed {
+                group_name: name("Dogs"),
+                by: name(ADMINISTRATOR),
+                banned: false,
+            }
+        );
+        let (_, groups) = http(admin, "GET", "/groups").await;
+        assert_eq!(groups.trim(), "[]");
+
+        let (status, _) = http(admin, "POST", "/groups/Dogs/close").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = http(admin, "GET", "/groups/Dogs/close").await;
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    });
+}
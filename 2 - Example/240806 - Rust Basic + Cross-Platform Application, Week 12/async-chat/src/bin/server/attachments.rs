@@ -0,0 +1,427 @@
+use async_chat::attachment::{self, CHUNK_SIZE};
+use async_chat::Attachment;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Where and how to keep files attached to messages.
+///
+/// Each attachment is saved under 'directory' in a file named by its id,
+/// beside a JSON file of its details and the groups it was posted to, named
+/// the same with '.json' added. Attachments with the same contents share
+/// one file.
+#[derive(Clone, Debug)]
+pub struct AttachmentConfig {
+    pub directory: PathBuf,
+    /// The largest attachment accepted, in bytes.
+    pub max_size: u64,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> AttachmentConfig {
+        AttachmentConfig {
+            directory: PathBuf::new(),
+            max_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// The longest file name accepted, in bytes.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// How many uploads one connection may have under way at once.
+const MAX_UPLOADS: usize = 4;
+
+/// How many downloads one connection may have under way at once. Each holds
+/// its whole file in memory until it has been sent.
+const MAX_DOWNLOADS: usize = 4;
+
+pub struct AttachmentStore {
+    config: AttachmentConfig,
+    /// Held while updating an attachment's details, so that two groups
+    /// sharing the same file at once both get recorded.
+    saving: Mutex<()>,
+}
+
+/// What is saved beside each attachment. Only members of 'groups' may
+/// download it.
+#[derive(Serialize, Deserialize)]
+struct Details {
+    attachment: Attachment,
+    groups: Vec<Arc<String>>,
+}
+
+impl AttachmentStore {
+    pub fn open(config: &AttachmentConfig) -> io::Result<AttachmentStore> {
+        fs::create_dir_all(&config.directory)?;
+        Ok(AttachmentStore {
+            config: config.clone(),
+            saving: Mutex::new(()),
+        })
+    }
+
+    /// Check that a finished upload arrived intact, and save it. Write to a
+    /// temporary file and rename it, so a half-written attachment is never
+    /// served.
+    pub fn save(&self, finished: &Finished) -> Result<(), String> {
+        let (upload, attachment) = (finished.upload, &finished.attachment);
+        if attachment::digest(&finished.data) != attachment.id {
+            return Err(format!("Upload {upload} does not match its checksum"));
+        }
+
+        let path = self.config.directory.join(&attachment.id);
+        let write = || -> io::Result<()> {
+            let temporary = path.with_extension("part");
+            fs::write(&temporary, &finished.data)?;
+            fs::rename(&temporary, &path)?;
+
+            let _saving = self.saving.lock().unwrap();
+            let mut details = self.details(&attachment.id).unwrap_or(Details {
+                attachment: attachment.clone(),
+                groups: Vec::new(),
+            });
+            if !details.groups.contains(&finished.group_name) {
+                details.groups.push(finished.group_name.clone());
+            }
+            fs::write(path.with_extension("json"), serde_json::to_vec(&details)?)
+        };
+
+        write().map_err(|error| format!("Could not save upload {upload}: {error}"))
+    }
+
+    /// Return the details and contents of the attachment 'id', if
+    /// 'permitted' accepts the groups it was posted to.
+    pub fn load(
+        &self,
+        id: &str,
+        permitted: impl FnOnce(&[Arc<String>]) -> Result<(), String>,
+    ) -> Result<(Attachment, Vec<u8>), String> {
+        let missing = || format!("No attachment '{id}'");
+        if !attachment::is_valid_id(id) {
+            return Err(missing());
+        }
+
+        let details = self.details(id).map_err(|_| missing())?;
+        permitted(&details.groups)?;
+        let data = fs::read(self.config.directory.join(id)).map_err(|_| missing())?;
+
+        Ok((details.attachment, data))
+    }
+
+    fn details(&self, id: &str) -> io::Result<Details> {
+        let path = self.config.directory.join(id).with_extension("json");
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// One connection's uploads under way.
+pub struct Uploads {
+    uploads: HashMap<u64, Upload>,
+}
+
+/// An upload that has been offered but not yet completed.
+struct Upload {
+    group_name: Arc<String>,
+    message: Arc<String>,
+    attachment: Attachment,
+    data: Vec<u8>,
+}
+
+/// An upload that has arrived in full, ready to check and save.
+pub struct Finished {
+    pub upload: u64,
+    pub group_name: Arc<String>,
+    pub message: Arc<String>,
+    pub attachment: Attachment,
+    pub data: Vec<u8>,
+}
+
+impl Uploads {
+    pub fn new() -> Uploads {
+        Uploads {
+            uploads: HashMap::new(),
+        }
+    }
+
+    /// Begin upload number 'upload', unless it's too large or the
+    /// connection has too many under way.
+    pub fn offer(
+        &mut self,
+        store: &AttachmentStore,
+        upload: u64,
+        group_name: Arc<String>,
+        message: Arc<String>,
+        attachment: Attachment,
+    ) -> Result<(), String> {
+        if attachment.size > store.config.max_size {
+            return Err(format!(
+                "Attachment of {} bytes exceeds the limit of {}",
+                attachment.size, store.config.max_size
+            ));
+        }
+        if attachment.file_name.is_empty() || attachment.file_name.len() > MAX_FILE_NAME_LENGTH {
+            return Err(format!("Invalid file name '{}'", attachment.file_name));
+        }
+        if !attachment::is_valid_id(&attachment.id) {
+            return Err(format!("Invalid attachment id '{}'", attachment.id));
+        }
+        if self.uploads.contains_key(&upload) {
+            return Err(format!("Upload {upload} is already under way"));
+        }
+        if self.uploads.len() >= MAX_UPLOADS {
+            return Err("Too many uploads under way".to_string());
+        }
+
+        let upload_state = Upload {
+            group_name,
+            message,
+            data: Vec::with_capacity(attachment.size as usize),
+            attachment,
+        };
+        self.uploads.insert(upload, upload_state);
+        Ok(())
+    }
+
+    /// Add a base64-encoded chunk to upload number 'upload'. An upload that
+    /// goes wrong is abandoned.
+    pub fn chunk(&mut self, upload: u64, data: &str) -> Result<(), String> {
+        let state = self
+            .uploads
+            .get_mut(&upload)
+            .ok_or_else(|| format!("No upload {upload} under way"))?;
+
+        let result = attachment::decode(data).and_then(|chunk| {
+            if chunk.len() > CHUNK_SIZE {
+                return Err(format!("Chunks may be at most {CHUNK_SIZE} bytes"));
+            }
+            if (state.data.len() + chunk.len()) as u64 > state.attachment.size {
+                return Err(format!("Upload {upload} is larger than offered"));
+            }
+            state.data.extend_from_slice(&chunk);
+            Ok(())
+        });
+
+        if result.is_err() {
+            self.uploads.remove(&upload);
+        }
+        result
+    }
+
+    /// Finish upload number 'upload', checking it's the size offered. It
+    /// still needs saving with 'AttachmentStore::save'.
+    pub fn complete(&mut self, upload: u64) -> Result<Finished, String> {
+        let state = self
+            .uploads
+            .remove(&upload)
+            .ok_or_else(|| format!("No upload {upload} under way"))?;
+
+        if state.data.len() as u64 != state.attachment.size {
+            return Err(format!("Upload {upload} is smaller than offered"));
+        }
+
+        Ok(Finished {
+            upload,
+            group_name: state.group_name,
+            message: state.message,
+            attachment: state.attachment,
+            data: state.data,
+        })
+    }
+}
+
+/// One connection's downloads under way, each sent by a task of its own.
+pub struct Downloads {
+    under_way: Arc<AtomicUsize>,
+}
+
+/// A place among a connection's downloads, given up when dropped.
+pub struct DownloadSlot {
+    under_way: Arc<AtomicUsize>,
+}
+
+impl Downloads {
+    pub fn new() -> Downloads {
+        Downloads {
+            under_way: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Take a place for a new download, unless the connection has too many
+    /// under way.
+    pub fn start(&self) -> Result<DownloadSlot, String> {
+        let taken = self
+            .under_way
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_DOWNLOADS).then_some(n + 1)
+            });
+        if taken.is_err() {
+            return Err("Too many downloads under way".to_string());
+        }
+
+        Ok(DownloadSlot {
+            under_way: self.under_way.clone(),
+        })
+    }
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        self.under_way.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[test]
+fn test_attachments() {
+    use crate::harness::{name, next_packet, scratch_dir, TestServer};
+    use async_chat::attachment::Download;
+    use async_chat::runtime;
+    use async_chat::{FromClient, FromServer};
+
+    let directory = scratch_dir("files");
+
+    runtime::block_on(async {
+        let server = TestServer::start(&[
+            "--attachments",
+            directory.to_str().unwrap(),
+            "--max-attachment-size",
+            "100000",
+        ])
+        .await;
+        let mut client = server.member("fido", "Dogs").await;
+
+        // A file spanning several chunks arrives intact, and is posted.
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        client
+            .upload("Dogs", "bones.bin", "my bones", &data)
+            .await
+            .unwrap();
+        let attachment = match next_packet(&mut client).await.unwrap() {
+            FromServer::Message {
+                message,
+                attachment: Some(attachment),
+                ..
+            } => {
+                assert_eq!(*message, "my bones");
+                attachment
+            }
+            packet => panic!("expected a message with an attachment, got {packet:?}"),
+        };
+        assert_eq!(attachment.file_name, "bones.bin");
+        assert_eq!(attachment.size, 100_000);
+
+        client.download(&attachment.id).await.unwrap();
+        let mut download = match next_packet(&mut client).await.unwrap() {
+            FromServer::Offer { attachment } => Download::new(attachment),
+            packet => panic!("expected an offer, got {packet:?}"),
+        };
+        loop {
+            match next_packet(&mut client).await.unwrap() {
+                FromServer::Chunk { data, .. } => download.push(&data).unwrap(),
+                FromServer::Complete { .. } => break,
+                packet => panic!("expected a chunk, got {packet:?}"),
+            }
+        }
+        assert_eq!(download.finish().unwrap(), data);
+
+        // Damaged, oversized and unknown files are refused.
+        let offer = |upload, size, id: String| FromClient::Offer {
+            upload,
+            group_name: name("Dogs"),
+            file_name: "bad.txt".to_string(),
+            size,
+            id,
+            message: name(""),
+        };
+        for request in [
+            offer(7, 3, attachment::digest(b"abd")),
+            FromClient::Chunk {
+                upload: 7,
+                data: attachment::encode(b"abc"),
+            },
+            FromClient::Complete { upload: 7 },
+            offer(8, 100_001, attachment::digest(b"")),
+            FromClient::Download {
+                id: attachment::digest(b"nothing"),
+            },
+        ] {
+            client.send(&request).await.unwrap();
+        }
+
+        let mut errors = Vec::new();
+        while errors.len() < 3 {
+            if let FromServer::Error(message) = next_packet(&mut client).await.unwrap() {
+                errors.push(message);
+            }
+        }
+        assert!(errors[0].contains("checksum"), "{}", errors[0]);
+        assert!(errors[1].contains("exceeds the limit"), "{}", errors[1]);
+        assert!(errors[2].starts_with("No attachment"), "{}", errors[2]);
+    });
+
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_attachment_access() {
+    use crate::harness::{name, next_packet, scratch_dir, TestServer};
+    use async_chat::runtime;
+    use async_chat::{FromClient, FromServer};
+
+    let directory = scratch_dir("secret-files");
+
+    runtime::block_on(async {
+        let server = TestServer::start(&["--attachments", directory.to_str().unwrap()]).await;
+
+        // The owner of a private group bans rex from it, and posts a file.
+        let mut owner = server.client("owner").await;
+        for request in [
+            FromClient::Create {
+                group_name: name("Secrets"),
+                private: true,
+            },
+            FromClient::Ban {
+                group_name: name("Secrets"),
+                nickname: name("rex"),
+            },
+        ] {
+            owner.send(&request).await.unwrap();
+        }
+        owner
+            .upload("Secrets", "plans.txt", "the plans", b"dig under the fence")
+            .await
+            .unwrap();
+        let id = loop {
+            if let Some(FromServer::Message {
+                attachment: Some(attachment),
+                ..
+            }) = next_packet(&mut owner).await
+            {
+                break attachment.id;
+            }
+        };
+
+        // Neither an outsider nor rex can download it.
+        let mut spot = server.client("spot").await;
+        let mut rex = server.client("rex").await;
+        for client in [&mut spot, &mut rex] {
+            client.download(&id).await.unwrap();
+        }
+        assert_eq!(
+            [next_packet(&mut spot).await, next_packet(&mut rex).await],
+            [
+                Some(FromServer::Error(
+                    "Not a member of private group 'Secrets'".to_string()
+                )),
+                Some(FromServer::Error(
+                    "You are banned from group 'Secrets'".to_string()
+                )),
+            ]
+        );
+    });
+
+    fs::remove_dir_all(&directory).unwrap();
+}
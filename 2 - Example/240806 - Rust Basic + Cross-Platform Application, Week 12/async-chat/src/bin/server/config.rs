@@ -0,0 +1,267 @@
+use crate::attachments::AttachmentConfig;
+use crate::history::HistoryConfig;
+use crate::message_log::LogConfig;
+use crate::outbound::OutboundConfig;
+use crate::plugins;
+use crate::rate_limit::RateLimitConfig;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: server ADDRESS [OPTIONS]
+
+Options:
+    --ws-address ADDRESS    Also accept WebSocket clients on ADDRESS
+    --admin ADDRESS         Serve metrics and admin commands over HTTP on
+                            ADDRESS, which should be reachable only by
+                            operators, such as 127.0.0.1:9090
+    --relay-address ADDRESS Accept links from other servers on ADDRESS, and
+                            share groups' messages with them
+    --peer ADDRESS          Link to the server relaying on ADDRESS, keeping the
+                            link open. May be repeated.
+    --node NAME             Name this server uniquely among those it's linked
+                            with (default: a random name)
+    --history-size N        Keep the last N messages of each group (default 100)
+    --history-age SECONDS   Forget messages older than SECONDS
+    --log-dir DIR           Save messages and who may access each group under
+                            DIR, and restore them on startup
+    --log-segment-size N    Start a new log file after N bytes (default 1048576)
+    --log-segments N        Keep the last N log files per group (default 10)
+    --log-age SECONDS       Delete log files last written over SECONDS ago
+    --attachments DIR       Let clients share files, keeping them under DIR
+    --max-attachment-size N Reject files over N bytes (default 10485760)
+    --tls-cert FILE         Accept only TLS connections, presenting the PEM
+                            certificate chain in FILE
+    --tls-key FILE          The PEM private key for '--tls-cert'
+    --outbound-queue N      Let up to N packets wait for each client (default 1000)
+    --slow-client-policy P  When a client's queue is full, 'drop-oldest' packets
+                            or 'disconnect' the client (default drop-oldest)
+    --post-rate N           Let each client send N messages a second (default 5)
+    --post-burst N          ...or up to N at once after a pause (default 10)
+    --group-post-rate N     Let each group accept N messages a second (default 50)
+    --group-post-burst N    ...or up to N at once after a pause (default 100)
+    --max-message-length N  Reject messages over N bytes (default 4096)
+    --max-violations N      Disconnect clients that break these limits more
+                            than N times a minute (default 10)
+    --users FILE            Only let in the users listed in FILE
+    --idle-timeout SECONDS  Close connections that haven't logged in after
+                            SECONDS, or that send nothing for that long
+                            (default 60, 0 for never)
+    --plugin NAME           Run the built-in bot NAME: 'help' answers '!help'
+                            and 'echo' answers '!echo TEXT'. May be repeated
+                            to run several.
+
+Commands:
+    server hash-password NICKNAME
+                            Read a password from standard input, and print a
+                            line for '--users' giving it to NICKNAME
+    server new-token NICKNAME
+                            Print a new token for NICKNAME, and a line for
+                            '--users' that accepts it";
+
+/// The server's settings, as given on the command line.
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub address: String,
+    pub ws_address: Option<String>,
+    /// Where to serve the admin interface, if anywhere.
+    pub admin_address: Option<String>,
+    /// Where to accept links from other servers, if anywhere.
+    pub relay_address: Option<String>,
+    /// The other servers to open links to.
+    pub peers: Vec<String>,
+    /// 'None' to pick a random name.
+    pub node: Option<String>,
+    pub history: HistoryConfig,
+    /// 'None' unless '--log-dir' was given.
+    pub log: Option<LogConfig>,
+    /// 'None' unless '--attachments' was given.
+    pub attachments: Option<AttachmentConfig>,
+    /// 'None' unless '--tls-cert' and '--tls-key' were given.
+    pub tls: Option<TlsConfig>,
+    pub outbound: OutboundConfig,
+    pub limits: RateLimitConfig,
+    /// The accounts file, if logins need credentials.
+    pub users: Option<PathBuf>,
+    /// 'None' if connections may stay silent forever.
+    pub idle_timeout: Option<Duration>,
+    /// The built-in plugins to run.
+    pub plugins: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ServerConfig {
+    /// Parse the command-line arguments that follow the program name.
+    pub fn from_args<I>(args: I) -> Result<ServerConfig, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        let mut address = None;
+        let mut ws_address = None;
+        let mut admin_address = None;
+        let mut relay_address = None;
+        let mut peers = Vec::new();
+        let mut node = None;
+        let mut history = HistoryConfig::default();
+        let mut log_dir = None;
+        let mut log = LogConfig::default();
+        let mut attachments_dir = None;
+        let mut attachments = AttachmentConfig::default();
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        let mut outbound = OutboundConfig::default();
+        let mut limits = RateLimitConfig::default();
+        let mut users = None;
+        let mut idle_timeout = Some(Duration::from_secs(60));
+        let mut plugins = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--ws-address" => {
+                    ws_address = Some(parse_value(&arg, args.next())?);
+                }
+                "--admin" => {
+                    admin_address = Some(parse_value(&arg, args.next())?);
+                }
+                "--relay-address" => {
+                    relay_address = Some(parse_value(&arg, args.next())?);
+                }
+                "--peer" => {
+                    peers.push(parse_value(&arg, args.next())?);
+                }
+                "--node" => {
+                    node = Some(parse_value(&arg, args.next())?);
+                }
+                "--history-size" => {
+                    history.max_messages = parse_value(&arg, args.next())?;
+                }
+                "--history-age" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    history.max_age = Some(Duration::from_secs(seconds));
+                }
+                "--log-dir" => {
+                    log_dir = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                "--log-segment-size" => {
+                    log.max_segment_bytes = parse_value(&arg, args.next())?;
+                }
+                "--log-segments" => {
+                    log.max_segments = parse_value(&arg, args.next())?;
+                }
+                "--log-age" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    log.max_age = Some(Duration::from_secs(seconds));
+                }
+                "--attachments" => {
+                    attachments_dir = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                "--max-attachment-size" => {
+                    attachments.max_size = parse_value(&arg, args.next())?;
+                }
+                "--tls-cert" => {
+                    tls_cert = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                "--tls-key" => {
+                    tls_key = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                "--outbound-queue" => {
+                    outbound.capacity = parse_value(&arg, args.next())?;
+                }
+                "--slow-client-policy" => {
+                    outbound.policy = parse_value(&arg, args.next())?;
+                }
+                "--post-rate" => {
+                    limits.client.per_second = parse_value(&arg, args.next())?;
+                }
+                "--post-burst" => {
+                    limits.client.burst = parse_value(&arg, args.next())?;
+                }
+                "--group-post-rate" => {
+                    limits.group.per_second = parse_value(&arg, args.next())?;
+                }
+                "--group-post-burst" => {
+                    limits.group.burst = parse_value(&arg, args.next())?;
+                }
+                "--max-message-length" => {
+                    limits.max_message_length = parse_value(&arg, args.next())?;
+                }
+                "--max-violations" => {
+                    limits.max_violations = parse_value(&arg, args.next())?;
+                }
+                "--idle-timeout" => {
+                    let seconds: f64 = parse_value(&arg, args.next())?;
+                    let timeout = Duration::try_from_secs_f64(seconds)
+                        .map_err(|_| format!("Invalid value '{seconds}' for '{arg}'"))?;
+                    idle_timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+                }
+                "--plugin" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    if !plugins::BUILT_IN.contains(&name.as_str()) {
+                        return Err(format!("Unknown plugin '{name}'"));
+                    }
+                    if plugins.contains(&name) {
+                        return Err(format!("Plugin '{name}' given more than once"));
+                    }
+                    plugins.push(name);
+                }
+                "--users" => {
+                    users = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option '{arg}'")),
+                _ if address.is_none() => address = Some(arg),
+                _ => return Err(format!("Unexpected argument '{arg}'")),
+            }
+        }
+
+        if outbound.capacity == 0 {
+            return Err("'--outbound-queue' must be at least 1".to_string());
+        }
+
+        for rate in [limits.client, limits.group] {
+            if rate.per_second <= 0.0 || rate.burst == 0 {
+                return Err("Message rates and bursts must be positive".to_string());
+            }
+        }
+
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return Err("'--tls-cert' and '--tls-key' must be given together".to_string()),
+        };
+
+        Ok(ServerConfig {
+            address: address.ok_or("Missing ADDRESS")?,
+            ws_address,
+            admin_address,
+            relay_address,
+            peers,
+            node,
+            history,
+            log: log_dir.map(|directory| LogConfig { directory, ..log }),
+            attachments: attachments_dir.map(|directory| AttachmentConfig {
+                directory,
+                ..attachments
+            }),
+            tls,
+            outbound,
+            limits,
+            users,
+            idle_timeout,
+            plugins,
+        })
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for '{option}'"))?;
+
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{value}' for '{option}'"))
+}
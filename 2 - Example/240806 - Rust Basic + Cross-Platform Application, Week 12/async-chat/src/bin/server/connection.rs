@@ -1,60 +1,854 @@
-use async_chat::utils::{self, ChatResult};
-use async_chat::{FromClient, FromServer};
-use async_std::io::BufReader;
-use async_std::net::TcpStream;
-use async_std::prelude::*;
-use async_std::sync::Arc;
-use async_std::sync::Mutex;
+use async_chat::attachment::CHUNK_SIZE;
+use async_chat::codec;
+use async_chat::protocol;
+use async_chat::runtime;
+use async_chat::utils::{ChatResult, ChatStream};
+use async_chat::{attachment, Attachment, Credential, FromClient, FromServer};
+use futures::io::BufReader;
+use futures::prelude::*;
+use futures_lite::FutureExt as _;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::attachments::{AttachmentStore, Downloads, Uploads};
+use crate::connection_table::{Registration, Transport};
+use crate::group::Group;
 use crate::group_table::GroupTable;
+use crate::logging::log;
+use crate::outbound::Outbound;
+use crate::rate_limit::FloodGuard;
+use crate::state::ServerState;
+use crate::user_table::UserTable;
 
-pub async fn serve(socket: TcpStream, groups: Arc<GroupTable>) -> ChatResult<()> {
-    let outbound = Arc::new(Outbound::new(socket.clone()));
-    let buffered = BufReader::new(socket);
-    let mut from_client = utils::receive_as_json(buffered);
+/// How many messages a 'History' request returns when it gives no limit.
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+/// How many times a client may give the wrong credentials before it is
+/// disconnected.
+const MAX_LOGIN_FAILURES: u32 = 3;
+
+pub async fn serve<S>(socket: S, peer: SocketAddr, state: Arc<ServerState>) -> ChatResult<()>
+where
+    S: ChatStream + 'static,
+{
+    let (reader, mut writer) = socket.split();
+    let mut buffered = BufReader::new(reader);
+    let codec = codec::accept_codec(&mut buffered, &mut writer).await?;
+
+    let outbound = Arc::new(Outbound::new(
+        writer,
+        codec,
+        state.outbound,
+        state.metrics.clone(),
+    ));
+    let from_client = codec.receive(buffered);
+
+    serve_requests(from_client, outbound, peer, Transport::Tcp, state).await
+}
+
+/// Carry out the requests arriving on 'from_client', sending replies to
+/// 'outbound'. This is the part of serving a connection that doesn't care
+/// how packets are carried.
+///
+/// Returns early if 'outbound' is closed, as happens when the client falls
+/// too far behind under 'SlowClientPolicy::Disconnect', or if the server is
+/// shutting down. Either way, everything queued for the client is sent before
+/// this returns.
+pub async fn serve_requests<S>(
+    from_client: S,
+    outbound: Arc<Outbound>,
+    peer: SocketAddr,
+    transport: Transport,
+    state: Arc<ServerState>,
+) -> ChatResult<()>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let registration = state.connections.open(peer, transport);
+    state
+        .metrics
+        .connections_opened
+        .fetch_add(1, Ordering::Relaxed);
+
+    let stop = Box::pin(async { outbound.closed().race(state.shutdown.triggered()).await });
+    let mut from_client = futures::StreamExt::take_until(from_client, stop);
+
+    let result = serve_client(&mut from_client, &outbound, &registration, &state).await;
+
+    if state.shutdown.is_triggered() {
+        let message = "The server is shutting down".to_string();
+        let _ = outbound.send(FromServer::Error(message));
+    }
+    outbound.finish().await;
+
+    result
+}
+
+async fn serve_client<S>(
+    from_client: &mut S,
+    outbound: &Arc<Outbound>,
+    registration: &Registration<'_>,
+    state: &Arc<ServerState>,
+) -> ChatResult<()>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let (groups, users) = (&state.groups, &state.users);
+
+    // A 'Hello', if any, must come first. After that, nothing but 'Login' is
+    // accepted until the client has a nickname, which it must have before the
+    // idle timeout runs out.
+    let mut first = true;
+    let mut login_failures = 0;
+    let login_deadline = state.idle_timeout.map(|timeout| Instant::now() + timeout);
+    let nickname = loop {
+        let limit =
+            login_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let request = match next_request(from_client, outbound, limit).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let is_first = std::mem::replace(&mut first, false);
+
+        let message = match request {
+            FromClient::Hello {
+                version,
+                capabilities,
+            } if is_first => match protocol::negotiate(version, &capabilities) {
+                Ok(agreement) => {
+                    outbound.set_agreement(agreement.clone());
+                    outbound.send(FromServer::Hello {
+                        version: agreement.version,
+                        capabilities: agreement.capabilities,
+                    })?;
+                    continue;
+                }
+                Err(message) => {
+                    outbound.send(FromServer::Error(message))?;
+                    return Ok(());
+                }
+            },
+            FromClient::Hello { .. } => "'Hello' must be the first request".to_string(),
+            FromClient::Ping => {
+                outbound.send(FromServer::Pong)?;
+                continue;
+            }
+            FromClient::Login {
+                nickname,
+                credential,
+            } => {
+                if nickname.is_empty() || nickname.contains(char::is_whitespace) {
+                    format!("Invalid nickname '{nickname}'")
+                } else if !authenticate(state, &nickname, credential).await {
+                    state.metrics.login_failures.fetch_add(1, Ordering::Relaxed);
+                    log!(
+                        Warn,
+                        "login_failed",
+                        peer = registration.peer,
+                        nickname = nickname
+                    );
+                    login_failures += 1;
+                    if login_failures >= MAX_LOGIN_FAILURES {
+                        let message = "Too many failed logins".to_string();
+                        outbound.send(FromServer::Error(message))?;
+                        return Err(format!("Too many failed logins as '{nickname}'").into());
+                    }
+                    "Incorrect nickname or credentials".to_string()
+                } else if users.login(nickname.clone(), outbound.clone()) {
+                    break nickname;
+                } else {
+                    format!("Nickname '{nickname}' is already in use")
+                }
+            }
+            _ => "You must log in first".to_string(),
+        };
+
+        outbound.send(FromServer::Error(message))?;
+    };
+
+    registration.set_nickname(nickname.clone());
+    state.metrics.logins.fetch_add(1, Ordering::Relaxed);
+    log!(
+        Info,
+        "logged_in",
+        peer = registration.peer,
+        nickname = nickname
+    );
+
+    let result = handle_requests(from_client, &nickname, outbound, state).await;
+
+    groups.leave_all(&nickname);
+    users.logout(&nickname);
+
+    result
+}
+
+/// Return the next request from the client, or 'None' if the stream has
+/// ended. If 'limit' is given and the client sends nothing for that long,
+/// tell it the connection is being closed, and return an error.
+async fn next_request<S>(
+    from_client: &mut S,
+    outbound: &Outbound,
+    limit: Option<Duration>,
+) -> ChatResult<Option<FromClient>>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let next = from_client.next();
+    let request = match limit {
+        Some(limit) => match runtime::timeout(limit, next).await {
+            Ok(request) => request,
+            Err(_) => {
+                let message = "Closing idle connection".to_string();
+                outbound.send(FromServer::Error(message))?;
+                return Err("Closed idle connection".into());
+            }
+        },
+        None => next.await,
+    };
+
+    request.transpose()
+}
+
+/// Return 'true' if 'credential' lets the client log in as 'nickname'.
+/// Anyone may log in if the server has no accounts.
+async fn authenticate(
+    state: &Arc<ServerState>,
+    nickname: &Arc<String>,
+    credential: Option<Credential>,
+) -> bool {
+    if state.accounts.is_none() {
+        return true;
+    }
+
+    let nickname = nickname.clone();
+    run_blocking(state, move |state| {
+        let accounts = state.accounts.as_ref().unwrap();
+        accounts.verify(&nickname, credential.as_ref())
+    })
+    .await
+}
+
+/// Run 'function' on a thread where blocking is allowed. Password hashing
+/// and whole-file reads and writes would hold up other connections if run
+/// on the async threads.
+async fn run_blocking<F, T>(state: &Arc<ServerState>, function: F) -> T
+where
+    F: FnOnce(&ServerState) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let state = state.clone();
+    runtime::spawn_blocking(move || function(&state)).await
+}
+
+async fn handle_requests<S>(
+    from_client: &mut S,
+    nickname: &Arc<String>,
+    outbound: &Arc<Outbound>,
+    state: &Arc<ServerState>,
+) -> ChatResult<()>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let (groups, users) = (&state.groups, &state.users);
+    let mut flood_guard = FloodGuard::new(&state.limits);
+    let mut uploads = Uploads::new();
+    let downloads = Downloads::new();
+
+    outbound.send(FromServer::LoggedIn {
+        nickname: nickname.clone(),
+    })?;
+
+    while let Some(request) = next_request(from_client, outbound, state.idle_timeout).await? {
+        if let Some(capability) = outbound.missing_capability(&request) {
+            let message = format!("The '{capability}' capability was not negotiated");
+            outbound.send(FromServer::Error(message))?;
+            continue;
+        }
+
+        // A post with an id gets an answer either way, so the client can
+        // tell which of its posts got through.
+        let post_id = match &request {
+            FromClient::Post { id: Some(id), .. } if outbound.supports(protocol::ACKS) => Some(*id),
+            _ => None,
+        };
+
+        if let Err(message) = flood_guard.check(&request) {
+            state
+                .metrics
+                .requests_rate_limited
+                .fetch_add(1, Ordering::Relaxed);
+            outbound.send(refusal(post_id, message))?;
+
+            if flood_guard.record_violation() {
+                let message = "Disconnected for breaking the rate limits".to_string();
+                outbound.send(FromServer::Error(message))?;
+                return Err(format!("Disconnected '{nickname}' for flooding").into());
+            }
+            continue;
+        }
 
-    while let Some(request_result) = from_client.next().await {
-        let request = request_result?;
         let result = match request {
-            FromClient::Join { group_name } => {
-                let group = groups.get_or_create(group_name);
-                group.join(outbound.clone());
+            FromClient::Hello { .. } => Err("'Hello' must be the first request".to_string()),
+            FromClient::Login { .. } => Err(format!("Already logged in as '{nickname}'")),
+            FromClient::Ping => {
+                outbound.send(FromServer::Pong)?;
                 Ok(())
             }
+            FromClient::Join { group_name } => {
+                groups
+                    .join(group_name, nickname.clone(), outbound.clone())
+                    .await
+            }
+            FromClient::Leave { group_name } => {
+                if groups.leave(&group_name, nickname) {
+                    Ok(())
+                } else {
+                    Err(format!("Not a member of group '{group_name}'"))
+                }
+            }
             FromClient::Post {
                 group_name,
                 message,
-            } => match groups.get(&group_name) {
-                Some(group) => {
-                    group.post(message);
+                ..
+            } => visible_group(groups, &group_name, nickname).and_then(|group| {
+                group.post(nickname.clone(), message.clone())?;
+                state.plugins.on_post(&group, nickname, &message);
+                Ok(())
+            }),
+            FromClient::Edit {
+                group_name,
+                seq,
+                message,
+            } => visible_group(groups, &group_name, nickname)
+                .and_then(|group| group.edit(nickname, seq, message)),
+            FromClient::Delete { group_name, seq } => visible_group(groups, &group_name, nickname)
+                .and_then(|group| group.delete(nickname, seq)),
+            FromClient::React {
+                group_name,
+                seq,
+                reaction,
+            } => visible_group(groups, &group_name, nickname)
+                .and_then(|group| group.react(nickname, seq, reaction)),
+            FromClient::Offer {
+                upload,
+                group_name,
+                file_name,
+                size,
+                id,
+                message,
+            } => attachment_store(state).and_then(|store| {
+                visible_group(groups, &group_name, nickname)?;
+                let attachment = Attachment {
+                    id,
+                    file_name,
+                    size,
+                };
+                uploads.offer(store, upload, group_name, message, attachment)
+            }),
+            FromClient::Chunk { upload, data } => uploads.chunk(upload, &data),
+            FromClient::Complete { upload } => {
+                share_upload(state, &mut uploads, upload, nickname).await
+            }
+            FromClient::Download { id } => {
+                start_download(state, &downloads, outbound, nickname, id)
+            }
+            FromClient::DirectMessage { to, message } => match users.get(&to) {
+                Some(recipient) if !recipient.supports(protocol::DIRECT_MESSAGES) => {
+                    Err(format!("User '{to}' can't receive direct messages"))
+                }
+                Some(recipient) => {
+                    let packet = FromServer::DirectMessage {
+                        from: nickname.clone(),
+                        message,
+                    };
+
+                    // A failure here is the recipient's connection going
+                    // away, which shouldn't end the sender's connection.
+                    recipient
+                        .send(packet)
+                        .map_err(|_| format!("Could not deliver message to '{to}'"))?;
+                    state
+                        .metrics
+                        .direct_messages
+                        .fetch_add(1, Ordering::Relaxed);
                     Ok(())
                 }
+                None => Err(format!("User '{to}' is not online")),
+            },
+            FromClient::ListGroups => {
+                let group_names = groups.names(nickname);
+                outbound.send(FromServer::Groups { group_names })?;
+                Ok(())
+            }
+            FromClient::History {
+                group_name,
+                before,
+                limit,
+            } => match visible_group(groups, &group_name, nickname) {
+                Ok(group) => {
+                    let messages = group.history(before, limit.unwrap_or(DEFAULT_HISTORY_LIMIT));
+                    outbound.send(FromServer::History {
+                        group_name,
+                        messages,
+                    })?;
+                    Ok(())
+                }
+                Err(message) => Err(message),
+            },
+            FromClient::ListMembers { group_name } => {
+                match visible_group(groups, &group_name, nickname) {
+                    Ok(group) => {
+                        let nicknames = group.member_names();
+                        outbound.send(FromServer::Members {
+                            group_name,
+                            nicknames,
+                        })?;
+                        Ok(())
+                    }
+                    Err(message) => Err(message),
+                }
+            }
+            FromClient::Create {
+                group_name,
+                private,
+            } => {
+                groups
+                    .create(group_name, nickname.clone(), private, outbound.clone())
+                    .await
+            }
+            FromClient::Invite {
+                group_name,
+                nickname: invitee,
+            } => match groups.get(&group_name) {
+                Some(group) => group.invite(nickname, invitee.clone()).map(|()| {
+                    let by = nickname.clone();
+                    notify(users, &invitee, FromServer::Invited { group_name, by });
+                }),
+                None => Err(format!("Group '{group_name}' does not exist")),
+            },
+            FromClient::Kick {
+                group_name,
+                nickname: target,
+            } => remove_member(groups, users, group_name, nickname, target, false),
+            FromClient::Ban {
+                group_name,
+                nickname: target,
+            } => remove_member(groups, users, group_name, nickname, target, true),
+            FromClient::SetRole {
+                group_name,
+                nickname: target,
+                role,
+            } => match groups.get(&group_name) {
+                Some(group) => group.set_role(nickname, target, role),
                 None => Err(format!("Group '{group_name}' does not exist")),
             },
         };
 
-        if let Err(message) = result {
-            let report = FromServer::Error(message);
-            outbound.send(report).await?;
+        match (result, post_id) {
+            (Ok(()), Some(id)) => outbound.send(FromServer::Ack { id })?,
+            (Ok(()), None) => {}
+            (Err(message), _) => outbound.send(refusal(post_id, message))?,
         }
     }
 
     Ok(())
 }
 
-pub struct Outbound(Mutex<TcpStream>);
+/// Report a failed request: as 'Rejected' if it was a post numbered
+/// 'post_id' for acknowledgement, or as an 'Error' otherwise.
+fn refusal(post_id: Option<u64>, reason: String) -> FromServer {
+    match post_id {
+        Some(id) => FromServer::Rejected { id, reason },
+        None => FromServer::Error(reason),
+    }
+}
+
+/// Return where attachments are kept, if this server accepts them.
+fn attachment_store(state: &ServerState) -> Result<&AttachmentStore, String> {
+    state
+        .attachments
+        .as_ref()
+        .ok_or_else(|| "This server does not accept attachments".to_string())
+}
+
+/// Finish upload number 'upload', save it, and post it to its group on
+/// behalf of 'nickname'.
+async fn share_upload(
+    state: &Arc<ServerState>,
+    uploads: &mut Uploads,
+    upload: u64,
+    nickname: &Arc<String>,
+) -> Result<(), String> {
+    attachment_store(state)?;
+    let finished = uploads.complete(upload)?;
+    visible_group(&state.groups, &finished.group_name, nickname)?;
+
+    let finished = run_blocking(state, move |state| {
+        let store = state.attachments.as_ref().unwrap();
+        store.save(&finished).map(|()| finished)
+    })
+    .await?;
+
+    let group = visible_group(&state.groups, &finished.group_name, nickname)?;
+    let message = finished.message;
+    group.share(nickname.clone(), message.clone(), finished.attachment)?;
+    state.plugins.on_post(&group, nickname, &message);
+    Ok(())
+}
+
+/// Read the attachment 'id', if 'nickname' may see one of the groups it was
+/// posted to.
+async fn load_attachment(
+    state: &Arc<ServerState>,
+    id: String,
+    nickname: &Arc<String>,
+) -> Result<(Attachment, Vec<u8>), String> {
+    attachment_store(state)?;
 
-impl Outbound {
-    pub fn new(to_client: TcpStream) -> Outbound {
-        Outbound(Mutex::new(to_client))
+    let nickname = nickname.clone();
+    run_blocking(state, move |state| {
+        let permitted = |group_names: &[Arc<String>]| {
+            let mut refusal = format!("No attachment '{id}'");
+            for group_name in group_names {
+                match visible_group(&state.groups, group_name, &nickname) {
+                    Ok(_group) => return Ok(()),
+                    Err(message) => refusal = message,
+                }
+            }
+            Err(refusal)
+        };
+        state.attachments.as_ref().unwrap().load(&id, permitted)
+    })
+    .await
+}
+
+/// Send the attachment 'id' to the client, from a task of its own so that
+/// the connection's other requests needn't wait for a large file.
+fn start_download(
+    state: &Arc<ServerState>,
+    downloads: &Downloads,
+    outbound: &Arc<Outbound>,
+    nickname: &Arc<String>,
+    id: String,
+) -> Result<(), String> {
+    attachment_store(state)?;
+    let slot = downloads.start()?;
+
+    let (state, outbound, nickname) = (state.clone(), outbound.clone(), nickname.clone());
+    runtime::spawn(async move {
+        // An error here means the connection has closed.
+        let _ = match load_attachment(&state, id, &nickname).await {
+            Ok((attachment, data)) => send_attachment(&outbound, attachment, &data).await,
+            Err(message) => outbound.send(FromServer::Error(message)),
+        };
+        drop(slot);
+    });
+
+    Ok(())
+}
+
+/// Send 'attachment', with contents 'data', to the client in chunks, waiting
+/// for room in its queue as needed.
+async fn send_attachment(
+    outbound: &Outbound,
+    attachment: Attachment,
+    data: &[u8],
+) -> ChatResult<()> {
+    let id = attachment.id.clone();
+    outbound
+        .send_transfer(FromServer::Offer { attachment })
+        .await?;
+    for piece in data.chunks(CHUNK_SIZE) {
+        let chunk = FromServer::Chunk {
+            id: id.clone(),
+            data: attachment::encode(piece),
+        };
+        outbound.send_transfer(chunk).await?;
     }
+    outbound.send_transfer(FromServer::Complete { id }).await
+}
+
+/// Return the group named 'group_name', if 'nickname' is allowed to see it.
+fn visible_group(
+    groups: &GroupTable,
+    group_name: &String,
+    nickname: &String,
+) -> Result<Arc<Group>, String> {
+    let group = groups
+        .get(group_name)
+        .ok_or_else(|| format!("Group '{group_name}' does not exist"))?;
 
-    pub async fn send(&self, packet: FromServer) -> ChatResult<()> {
-        let mut guard = self.0.lock().await;
+    group.check_access(nickname)?;
+    Ok(group)
+}
 
-        utils::send_as_json(&mut *guard, &packet).await?;
-        guard.flush().await?;
+/// Remove 'target' from 'group_name' on behalf of 'by', banning them if 'ban'
+/// is true, and tell them so if they are online.
+fn remove_member(
+    groups: &GroupTable,
+    users: &UserTable,
+    group_name: Arc<String>,
+    by: &Arc<String>,
+    target: Arc<String>,
+    ban: bool,
+) -> Result<(), String> {
+    groups.remove(&group_name, by, &target, ban)?;
 
-        Ok(())
+    let packet = FromServer::Removed {
+        group_name,
+        by: by.clone(),
+        banned: ban,
+    };
+    notify(users, &target, packet);
+
+    Ok(())
+}
+
+/// Send 'packet' to 'nickname' if they are online. Failing to reach them is
+/// their connection's problem, not the sender's.
+fn notify(users: &UserTable, nickname: &String, packet: FromServer) {
+    if let Some(recipient) = users.get(nickname) {
+        let _ = recipient.send(packet);
     }
 }
+
+#[test]
+fn test_old_and_new_clients_interoperate() {
+    use crate::config::ServerConfig;
+    use async_chat::codec::{Codec, PacketStream};
+    use async_chat::runtime::{self, TcpListener, TcpStream};
+    use async_chat::utils;
+    use futures::io::WriteHalf;
+
+    let config = ServerConfig::from_args(vec!["127.0.0.1:0".to_string()]).unwrap();
+    let state = Arc::new(ServerState::new(&config).unwrap());
+
+    async fn connect(
+        address: std::net::SocketAddr,
+        requests: &[FromClient],
+    ) -> (WriteHalf<TcpStream>, PacketStream<FromServer>) {
+        let socket = TcpStream::connect(&address.to_string()).await.unwrap();
+        let (reader, mut socket) = socket.split();
+        for request in requests {
+            utils::send_as_json(&mut socket, request).await.unwrap();
+        }
+
+        let replies = Codec::Json.receive(BufReader::new(reader));
+        (socket, replies)
+    }
+
+    async fn next_packet(replies: &mut PacketStream<FromServer>) -> Option<FromServer> {
+        replies.next().await.map(Result::unwrap)
+    }
+
+    let login = |nickname: &str| FromClient::Login {
+        nickname: Arc::new(nickname.to_string()),
+        credential: None,
+    };
+    let hello = |version: u32, capabilities: &[&str]| FromClient::Hello {
+        version,
+        capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+    };
+    let direct = |to: &str| FromClient::DirectMessage {
+        to: Arc::new(to.to_string()),
+        message: Arc::new("psst".to_string()),
+    };
+
+    runtime::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        runtime::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                runtime::spawn(serve(socket, peer, state.clone()));
+            }
+        });
+
+        // A client from before 'Hello' existed logs in directly.
+        let (mut old, mut from_old) = connect(address, &[login("old")]).await;
+        let reply = next_packet(&mut from_old).await.unwrap();
+        assert!(matches!(reply, FromServer::LoggedIn { .. }));
+
+        // A newer client is downgraded to the server's version, keeping only
+        // the capabilities both sides know.
+        let newer = hello(protocol::VERSION + 1, &["history", "telepathy"]);
+        let (mut new, mut from_new) = connect(address, &[newer, login("new")]).await;
+        assert_eq!(
+            next_packet(&mut from_new).await,
+            Some(FromServer::Hello {
+                version: protocol::VERSION,
+                capabilities: vec!["history".to_string()],
+            })
+        );
+        let reply = next_packet(&mut from_new).await.unwrap();
+        assert!(matches!(reply, FromServer::LoggedIn { .. }));
+
+        // The new client didn't ask for direct messages, so it can neither
+        // send nor receive them, and each side is told so.
+        utils::send_as_json(&mut old, &direct("new")).await.unwrap();
+        assert_eq!(
+            next_packet(&mut from_old).await,
+            Some(FromServer::Error(
+                "User 'new' can't receive direct messages".to_string()
+            ))
+        );
+
+        utils::send_as_json(&mut new, &direct("old")).await.unwrap();
+        assert_eq!(
+            next_packet(&mut from_new).await,
+            Some(FromServer::Error(
+                "The 'direct-messages' capability was not negotiated".to_string()
+            ))
+        );
+
+        // Posts that carry an id are answered by id, but only for clients
+        // that asked for 'acks'. Anyone else just hears about failures.
+        let post = |group_name: &str, id| FromClient::Post {
+            group_name: Arc::new(group_name.to_string()),
+            message: Arc::new("woof".to_string()),
+            id: Some(id),
+        };
+        let dogs = FromClient::Join {
+            group_name: Arc::new("Dogs".to_string()),
+        };
+        let acks = hello(protocol::VERSION, &[protocol::ACKS]);
+        let (mut acker, mut from_acker) = connect(address, &[acks, login("acker"), dogs]).await;
+        next_packet(&mut from_acker).await.unwrap();
+        next_packet(&mut from_acker).await.unwrap();
+
+        utils::send_as_json(&mut acker, &post("Cats", 1))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_packet(&mut from_acker).await,
+            Some(FromServer::Rejected {
+                id: 1,
+                reason: "Group 'Cats' does not exist".to_string(),
+            })
+        );
+        utils::send_as_json(&mut acker, &post("Dogs", 2))
+            .await
+            .unwrap();
+        let mut replies = vec![
+            next_packet(&mut from_acker).await.unwrap(),
+            next_packet(&mut from_acker).await.unwrap(),
+        ];
+        replies.retain(|reply| !matches!(reply, FromServer::Message { seq: 1, .. }));
+        assert_eq!(replies, [FromServer::Ack { id: 2 }]);
+
+        utils::send_as_json(&mut old, &post("Cats", 3))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_packet(&mut from_old).await,
+            Some(FromServer::Error("Group 'Cats' does not exist".to_string()))
+        );
+
+        // A client older than the server supports is turned away. It sends
+        // nothing after 'Hello': the server closes without reading any more,
+        // and unread requests would make it reset the connection instead.
+        let (_ancient, mut from_ancient) = connect(address, &[hello(0, &[])]).await;
+        let reply = next_packet(&mut from_ancient).await.unwrap();
+        assert!(matches!(reply, FromServer::Error(_)));
+        assert_eq!(next_packet(&mut from_ancient).await, None);
+    });
+}
+
+#[test]
+fn test_idle_connections_are_closed() {
+    use crate::config::ServerConfig;
+    use async_chat::codec::{Codec, PacketStream};
+    use async_chat::runtime::{self, TcpListener, TcpStream};
+    use async_chat::utils;
+
+    let args = ["127.0.0.1:0", "--idle-timeout", "0.2"];
+    let config = ServerConfig::from_args(args.map(str::to_string)).unwrap();
+    let state = Arc::new(ServerState::new(&config).unwrap());
+
+    let hello = FromClient::Hello {
+        version: protocol::VERSION,
+        capabilities: vec![protocol::HEARTBEAT.to_string()],
+    };
+    let login = FromClient::Login {
+        nickname: Arc::new("sleepy".to_string()),
+        credential: None,
+    };
+
+    async fn next_packet(replies: &mut PacketStream<FromServer>) -> Option<FromServer> {
+        replies.next().await.map(Result::unwrap)
+    }
+
+    runtime::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server_state = state.clone();
+        runtime::spawn(async move {
+            loop {
+                let (socket, peer) = listener.accept().await.unwrap();
+                runtime::spawn(serve(socket, peer, server_state.clone()));
+            }
+        });
+
+        let socket = TcpStream::connect(&address.to_string()).await.unwrap();
+        let (reader, mut socket) = socket.split();
+        for request in [&hello, &login] {
+            utils::send_as_json(&mut socket, request).await.unwrap();
+        }
+        let mut replies = Codec::Json.receive(BufReader::new(reader));
+
+        assert!(matches!(
+            next_packet(&mut replies).await,
+            Some(FromServer::Hello { .. })
+        ));
+        assert!(matches!(
+            next_packet(&mut replies).await,
+            Some(FromServer::LoggedIn { .. })
+        ));
+
+        // A client that pings in time stays connected.
+        runtime::sleep(Duration::from_millis(100)).await;
+        utils::send_as_json(&mut socket, &FromClient::Ping)
+            .await
+            .unwrap();
+        assert_eq!(next_packet(&mut replies).await, Some(FromServer::Pong));
+        runtime::sleep(Duration::from_millis(100)).await;
+        utils::send_as_json(&mut socket, &FromClient::Ping)
+            .await
+            .unwrap();
+        assert_eq!(next_packet(&mut replies).await, Some(FromServer::Pong));
+
+        // One that goes quiet is told why, and disconnected.
+        assert_eq!(
+            next_packet(&mut replies).await,
+            Some(FromServer::Error("Closing idle connection".to_string()))
+        );
+        assert_eq!(next_packet(&mut replies).await, None);
+
+        // So is one that never offered to send heartbeats.
+        let socket = TcpStream::connect(&address.to_string()).await.unwrap();
+        let (reader, mut socket) = socket.split();
+        let quiet = FromClient::Login {
+            nickname: Arc::new("quiet".to_string()),
+            credential: None,
+        };
+        utils::send_as_json(&mut socket, &quiet).await.unwrap();
+        let mut replies = Codec::Json.receive(BufReader::new(reader));
+        assert!(matches!(
+            next_packet(&mut replies).await,
+            Some(FromServer::LoggedIn { .. })
+        ));
+        assert_eq!(
+            next_packet(&mut replies).await,
+            Some(FromServer::Error("Closing idle connection".to_string()))
+        );
+        assert_eq!(next_packet(&mut replies).await, None);
+    });
+
+    assert!(state.users.get(&"sleepy".to_string()).is_none());
+    assert!(state.users.get(&"quiet".to_string()).is_none());
+}
@@ -0,0 +1,91 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::history;
+
+/// Every open connection, for the admin interface to list.
+pub struct ConnectionTable {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, ConnectionInfo>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub transport: Transport,
+    /// When the connection was opened, in milliseconds since the Unix epoch.
+    pub opened: u64,
+    /// 'None' until the client logs in.
+    pub nickname: Option<Arc<String>>,
+}
+
+/// How a client's packets are carried.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+/// Keeps a connection listed until dropped.
+pub struct Registration<'a> {
+    table: &'a ConnectionTable,
+    id: u64,
+    pub peer: SocketAddr,
+}
+
+impl ConnectionTable {
+    pub fn new() -> ConnectionTable {
+        ConnectionTable {
+            next_id: AtomicU64::new(1),
+            connections: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// List a new connection from 'peer', until the returned registration
+    /// is dropped.
+    pub fn open(&self, peer: SocketAddr, transport: Transport) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ConnectionInfo {
+            id,
+            peer,
+            transport,
+            opened: history::now_millis(),
+            nickname: None,
+        };
+
+        self.connections.lock().unwrap().insert(id, info);
+        Registration {
+            table: self,
+            id,
+            peer,
+        }
+    }
+
+    /// Return every open connection, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+}
+
+impl Registration<'_> {
+    pub fn set_nickname(&self, nickname: Arc<String>) {
+        if let Some(info) = self.table.connections.lock().unwrap().get_mut(&self.id) {
+            info.nickname = Some(nickname);
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.table.connections.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,507 @@
+//! Sharing groups between several server processes.
+//!
+//! Servers are joined by links: TCP connections carrying 'FromPeer' packets
+//! as newline-delimited JSON. A server accepts links on '--relay-address',
+//! and opens them to each '--peer', reconnecting whenever one drops.
+//!
+//! Every message posted on a server is sent over each of its links, and every
+//! message arriving over a link is delivered to the local group of the same
+//! name, if there is one, and passed on over the server's other links. So
+//! links may form any shape, including cycles: each server names the
+//! messages it originates, and drops any message it has already seen.
+//!
+//! Only posts are shared, without any attachments, and each server numbers
+//! them for itself, so edits, deletions and reactions stay where they're
+//! made. Membership, history requests and moderation stay with each server,
+//! and a message is lost on servers where its group doesn't exist yet, or
+//! where its sender is banned or uninvited.
+//!
+//! Links are neither encrypted nor authenticated, so relay addresses should
+//! be reachable only by other servers.
+
+use async_chat::runtime::{self, TcpListener, TcpStream};
+use async_chat::utils::{self, ChatResult};
+use async_chat::{FromPeer, PostedMessage};
+use futures::channel::mpsc;
+use futures::io::BufReader;
+use futures::prelude::*;
+use futures_lite::FutureExt as _;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::group_table::GroupTable;
+use crate::logging::log;
+use crate::metrics::Metrics;
+use crate::state::ServerState;
+
+/// How many messages from other servers to remember, to recognize them when
+/// they arrive again by another route.
+const SEEN_CAPACITY: usize = 10_000;
+
+/// How many packets may wait to be sent over one link. A peer that falls
+/// this far behind has its link closed, to be reopened once it recovers,
+/// rather than having its posts buffered without limit.
+const LINK_QUEUE_CAPACITY: usize = 10_000;
+
+/// How long to wait before reopening a link to a peer. Each failed attempt
+/// doubles the wait, up to 'MAX_BACKOFF'.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct Federation {
+    /// This server's name, unique among the servers it's linked with.
+    node: Arc<String>,
+    /// Chosen at random when the server starts, so that the ids it gives
+    /// messages aren't mistaken for those it gave before a restart.
+    epoch: u64,
+    /// The id for the next message posted here.
+    next_id: AtomicU64,
+    next_link: AtomicU64,
+    /// Each open link's queue of outgoing packets.
+    links: Mutex<HashMap<u64, LinkQueue>>,
+    seen: Mutex<Seen>,
+    metrics: Arc<Metrics>,
+}
+
+/// The most recent messages received from other servers, by origin, epoch
+/// and id.
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<(Arc<String>, u64, u64)>,
+    order: VecDeque<(Arc<String>, u64, u64)>,
+}
+
+impl Seen {
+    /// Remember the message 'id' from 'origin' in 'epoch'. Return false if it
+    /// was already known.
+    fn insert(&mut self, origin: &Arc<String>, epoch: u64, id: u64) -> bool {
+        let key = (origin.clone(), epoch, id);
+        if !self.ids.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The outgoing side of an open link.
+struct LinkQueue {
+    packets: mpsc::Sender<FromPeer>,
+    /// Signalled when 'packets' is full, to close the link.
+    overflowed: Arc<Notify>,
+}
+
+impl LinkQueue {
+    /// Queue 'packet', or have the link closed if the queue is full.
+    fn send(&mut self, packet: FromPeer) {
+        if let Err(error) = self.packets.try_send(packet) {
+            if error.is_full() {
+                self.overflowed.notify_one();
+            }
+        }
+    }
+}
+
+/// Keeps a link open for relaying until dropped.
+struct Link<'a> {
+    federation: &'a Federation,
+    id: u64,
+}
+
+impl Drop for Link<'_> {
+    fn drop(&mut self) {
+        self.federation.links.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Federation {
+    /// Create a federation for a server named 'node', or a random name if
+    /// 'None'.
+    pub fn new(node: Option<String>, metrics: Arc<Metrics>) -> ChatResult<Federation> {
+        let random = SystemRandom::new();
+        let mut bytes = [0; 8];
+
+        let node = match node {
+            Some(node) => node,
+            None => {
+                random
+                    .fill(&mut bytes)
+                    .map_err(|_| "Could not generate a node name")?;
+                bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+            }
+        };
+
+        random
+            .fill(&mut bytes)
+            .map_err(|_| "Could not generate an epoch")?;
+
+        Ok(Federation {
+            node: Arc::new(node),
+            epoch: u64::from_be_bytes(bytes),
+            next_id: AtomicU64::new(1),
+            next_link: AtomicU64::new(1),
+            links: Mutex::new(HashMap::new()),
+            seen: Mutex::new(Seen::default()),
+            metrics,
+        })
+    }
+
+    /// Send a message just posted to 'group_name' here to every linked
+    /// server.
+    pub fn relay(&self, group_name: &Arc<String>, posted: &PostedMessage) {
+        let mut links = self.links.lock().unwrap();
+        if links.is_empty() {
+            return;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        for link in links.values_mut() {
+            link.send(FromPeer::Post {
+                origin: self.node.clone(),
+                epoch: self.epoch,
+                id,
+                group_name: group_name.clone(),
+                sender: posted.sender.clone(),
+                message: posted.message.clone(),
+            });
+        }
+    }
+
+    fn open_link(&self, queue: LinkQueue) -> Link<'_> {
+        let id = self.next_link.fetch_add(1, Ordering::Relaxed);
+        self.links.lock().unwrap().insert(id, queue);
+        Link {
+            federation: self,
+            id,
+        }
+    }
+
+    /// Handle 'packet', received over 'link': deliver it to the local group,
+    /// and pass it on over every other link, unless it's been seen before.
+    fn receive(&self, link: &Link<'_>, packet: FromPeer, groups: &GroupTable) -> ChatResult<()> {
+        let FromPeer::Post {
+            origin,
+            epoch,
+            id,
+            group_name,
+            sender,
+            message,
+        } = packet
+        else {
+            return Err("'Hello' must be the first packet on a link".into());
+        };
+
+        if origin == self.node || !self.seen.lock().unwrap().insert(&origin, epoch, id) {
+            return Ok(());
+        }
+
+        for (&link_id, other) in self.links.lock().unwrap().iter_mut() {
+            if link_id != link.id {
+                other.send(FromPeer::Post {
+                    origin: origin.clone(),
+                    epoch,
+                    id,
+                    group_name: group_name.clone(),
+                    sender: sender.clone(),
+                    message: message.clone(),
+                });
+            }
+        }
+
+        if let Some(group) = groups.get(&group_name) {
+            match group.deliver(sender, message) {
+                Ok(()) => {
+                    self.metrics
+                        .messages_relayed
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Err(reason) => log!(
+                    Warn,
+                    "relayed_post_refused",
+                    origin = origin,
+                    reason = reason
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Accept links from other servers on 'listener'.
+pub async fn serve_relay(listener: TcpListener, state: Arc<ServerState>) {
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                log!(Warn, "relay_accept_failed", error = error);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        runtime::spawn(async move {
+            if let Err(error) = serve_link(socket, &state).await {
+                log!(Warn, "link_error", peer = peer, error = error);
+            }
+        });
+    }
+}
+
+/// Keep a link open to the server at 'address', reconnecting whenever it
+/// fails.
+pub async fn keep_linked(address: String, state: Arc<ServerState>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match TcpStream::connect(&address).await {
+            Ok(socket) => {
+                backoff = MIN_BACKOFF;
+                if let Err(error) = serve_link(socket, &state).await {
+                    log!(Warn, "link_error", peer = address, error = error);
+                }
+            }
+            Err(error) => log!(Warn, "link_failed", peer = address, error = error),
+        }
+
+        runtime::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Introduce this server over 'socket', then relay messages both ways until
+/// the link closes.
+async fn serve_link(socket: TcpStream, state: &ServerState) -> ChatResult<()> {
+    let federation = &state.federation;
+    let (reader, mut to_peer) = socket.split();
+    let mut from_peer = utils::receive_as_json(BufReader::new(reader));
+
+    let hello = FromPeer::Hello {
+        node: federation.node.clone(),
+    };
+    utils::send_as_json(&mut to_peer, &hello).await?;
+
+    let node = match from_peer.next().await {
+        Some(Ok(FromPeer::Hello { node })) => node,
+        Some(Err(error)) => return Err(error),
+        _ => return Err("The peer did not introduce itself".into()),
+    };
+    if node == federation.node {
+        return Err(format!("Refusing a link to '{node}', which is this server").into());
+    }
+
+    let (packets, mut outgoing) = mpsc::channel(LINK_QUEUE_CAPACITY);
+    let overflowed = Arc::new(Notify::new());
+    let link = federation.open_link(LinkQueue {
+        packets,
+        overflowed: overflowed.clone(),
+    });
+    log!(Info, "link_opened", node = node);
+
+    let sending = async {
+        while let Some(packet) = outgoing.next().await {
+            utils::send_as_json(&mut to_peer, &packet).await?;
+        }
+        Ok(())
+    };
+    let receiving = async {
+        while let Some(packet) = from_peer.next().await {
+            federation.receive(&link, packet?, &state.groups)?;
+        }
+        Ok(())
+    };
+
+    let overflow = async {
+        overflowed.notified().await;
+        Err(format!("The link to '{node}' fell too far behind").into())
+    };
+
+    let result = receiving.race(sending).race(overflow).await;
+    log!(Info, "link_closed", node = node);
+    result
+}
+
+#[test]
+fn test_federation() {
+    use crate::harness::{next_message, TestServer};
+
+    // Start a server named 'node', returning it and the address it accepts
+    // links on.
+    async fn start(node: &str) -> (TestServer, String) {
+        let server = TestServer::start(&["--node", node]).await;
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_address = relay.local_addr().unwrap().to_string();
+        runtime::spawn(serve_relay(relay, server.state.clone()));
+
+        (server, relay_address)
+    }
+
+    runtime::block_on(async {
+        // Link three servers in a cycle, so that every message could go
+        // round forever.
+        let (a, a_relay) = start("a").await;
+        let (b, b_relay) = start("b").await;
+        let (c, c_relay) = start("c").await;
+        runtime::spawn(keep_linked(b_relay, a.state.clone()));
+        runtime::spawn(keep_linked(c_relay, b.state.clone()));
+        runtime::spawn(keep_linked(a_relay, c.state.clone()));
+
+        for server in [&a, &b, &c] {
+            while server.state.federation.links.lock().unwrap().len() < 2 {
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let mut fido = a.member("fido", "Dogs").await;
+        let mut rex = b.member("rex", "Dogs").await;
+        let mut spot = c.member("spot", "Dogs").await;
+
+        fido.post("Dogs", "woof").await.unwrap();
+        rex.post("Dogs", "arf").await.unwrap();
+
+        // Each message arrives everywhere exactly once.
+        for client in [&mut fido, &mut rex, &mut spot] {
+            let mut heard = vec![next_message(client).await, next_message(client).await];
+            heard.sort();
+            assert_eq!(heard, ["fido: woof", "rex: arf"]);
+        }
+        spot.post("Dogs", "yip").await.unwrap();
+        for client in [&mut fido, &mut rex, &mut spot] {
+            assert_eq!(next_message(client).await, "spot: yip");
+        }
+
+        assert_eq!(a.state.metrics.messages_relayed.load(Ordering::Relaxed), 2);
+    });
+}
+
+#[test]
+fn test_banned_relayed_sender() {
+    use crate::harness::{name, next_message, TestServer};
+    use async_chat::FromClient;
+
+    runtime::block_on(async {
+        let a = TestServer::start(&["--node", "a"]).await;
+        let b = TestServer::start(&["--node", "b"]).await;
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let b_relay = relay.local_addr().unwrap().to_string();
+        runtime::spawn(serve_relay(relay, b.state.clone()));
+        runtime::spawn(keep_linked(b_relay, a.state.clone()));
+        for server in [&a, &b] {
+            while server.state.federation.links.lock().unwrap().is_empty() {
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        // fido owns Dogs on 'a', and bans rex from it.
+        let mut fido = a.member("fido", "Dogs").await;
+        fido.send(&FromClient::Ban {
+            group_name: name("Dogs"),
+            nickname: name("rex"),
+        })
+        .await
+        .unwrap();
+        fido.post("Dogs", "no rex").await.unwrap();
+        assert_eq!(next_message(&mut fido).await, "fido: no rex");
+
+        // Posting on 'b' doesn't get rex around the ban, though spot's post,
+        // sent over the same link after it, still arrives.
+        let mut rex = b.member("rex", "Dogs").await;
+        let mut spot = b.member("spot", "Dogs").await;
+        rex.post("Dogs", "sneaky").await.unwrap();
+        assert_eq!(next_message(&mut rex).await, "rex: sneaky");
+        spot.post("Dogs", "yip").await.unwrap();
+        assert_eq!(next_message(&mut fido).await, "spot: yip");
+        assert_eq!(a.state.metrics.messages_relayed.load(Ordering::Relaxed), 1);
+    });
+}
+
+#[test]
+fn test_restarted_node() {
+    use crate::harness::{next_message, TestServer};
+    use async_chat::client::ChatClient;
+
+    // Start a server named 'b', link it to the server relaying on
+    // 'a_relay', and have it post 'message' to Dogs, which 'fido' is
+    // listening to on the other server. Then shut 'b' down.
+    async fn post_from_b(a: &TestServer, a_relay: &str, fido: &mut ChatClient, message: &str) {
+        let b = TestServer::start(&["--node", "b"]).await;
+        let socket = TcpStream::connect(a_relay).await.unwrap();
+        let link = async {
+            let result = serve_link(socket, &b.state).await;
+            panic!("the link closed early: {result:?}");
+        };
+
+        let post = async {
+            for server in [a, &b] {
+                while server.state.federation.links.lock().unwrap().is_empty() {
+                    runtime::sleep(Duration::from_millis(10)).await;
+                }
+            }
+            let mut rex = b.member("rex", "Dogs").await;
+            rex.post("Dogs", message).await.unwrap();
+            assert_eq!(next_message(fido).await, format!("rex: {message}"));
+        };
+
+        // Finishing the post drops the link, closing it.
+        post.race(link).await;
+        b.shutdown().await;
+        while !a.state.federation.links.lock().unwrap().is_empty() {
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    runtime::block_on(async {
+        let a = TestServer::start(&["--node", "a"]).await;
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let a_relay = relay.local_addr().unwrap().to_string();
+        runtime::spawn(serve_relay(relay, a.state.clone()));
+        let mut fido = a.member("fido", "Dogs").await;
+
+        // The restarted server numbers its messages from the start again,
+        // but they still get through.
+        post_from_b(&a, &a_relay, &mut fido, "before").await;
+        post_from_b(&a, &a_relay, &mut fido, "after").await;
+    });
+}
+
+#[test]
+fn test_link_overflow() {
+    use async_chat::Reactions;
+
+    let federation = Federation::new(None, Arc::new(Metrics::new())).unwrap();
+    let (packets, mut outgoing) = mpsc::channel(0);
+    let overflowed = Arc::new(Notify::new());
+    let _link = federation.open_link(LinkQueue {
+        packets,
+        overflowed: overflowed.clone(),
+    });
+
+    let posted = PostedMessage {
+        sender: Arc::new("fido".to_string()),
+        message: Arc::new("woof".to_string()),
+        timestamp: 1,
+        seq: 1,
+        attachment: None,
+        edited: false,
+        deleted: false,
+        reactions: Reactions::new(),
+    };
+    let dogs = Arc::new("Dogs".to_string());
+
+    // The peer reads nothing, so once its queue fills, the link is closed.
+    federation.relay(&dogs, &posted);
+    assert!(overflowed.notified().now_or_never().is_none());
+    federation.relay(&dogs, &posted);
+    assert!(overflowed.notified().now_or_never().is_some());
+    assert!(outgoing.next().now_or_never().is_some());
+}
@@ -1,55 +1,886 @@
-use crate::connection::Outbound;
-use async_chat::FromServer;
-use async_std::task;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use crate::federation::Federation;
+use crate::history::{self, History, HistoryConfig};
+use crate::logging::log;
+use crate::message_log::{GroupLog, OpenedLog};
+use crate::metrics::Metrics;
+use crate::outbound::Outbound;
+use crate::rate_limit::{Rate, TokenBucket};
+use async_chat::runtime;
+use async_chat::{Attachment, FromServer, PostedMessage, Reactions, Role};
+use futures_lite::FutureExt as _;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, oneshot};
+
+/// The longest reaction accepted, in bytes.
+const MAX_REACTION_LENGTH: usize = 32;
 
 pub struct Group {
     name: Arc<String>,
-    sender: broadcast::Sender<Arc<String>>,
+    /// Carries new messages, and changes to earlier ones, to the members'
+    /// subscriber tasks.
+    sender: broadcast::Sender<FromServer>,
+    /// Each member's nickname, and the handle that stops its subscriber task
+    /// when dropped.
+    members: Mutex<HashMap<Arc<String>, oneshot::Sender<()>>>,
+    history: Mutex<History>,
+    /// Where posted messages are saved, if the server keeps logs on disk.
+    log: Option<GroupLog>,
+    /// Limits how fast posts are accepted, whoever sends them.
+    limit: Mutex<TokenBucket>,
+    /// Who may join, and who may moderate. When both are needed, this is
+    /// locked before 'members'.
+    access: Mutex<Access>,
+    metrics: Arc<Metrics>,
+    /// Where messages posted here are sent on to other servers.
+    federation: Arc<Federation>,
+}
+
+/// What the admin interface shows of a group.
+#[derive(Debug, Serialize)]
+pub struct GroupSummary {
+    pub name: Arc<String>,
+    pub owner: Option<Arc<String>>,
+    pub private: bool,
+    pub members: Vec<Arc<String>>,
+}
+
+/// Saved next to the group's message log whenever it changes, so that a
+/// restart doesn't open private groups to everyone or lift bans.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Access {
+    /// The user who created the group, or first joined it. 'None' only for
+    /// groups that no one has joined yet.
+    owner: Option<Arc<String>>,
+    moderators: HashSet<Arc<String>>,
+    /// Invite-only groups can only be joined by moderators and invitees, and
+    /// are hidden from everyone else.
+    private: bool,
+    invited: HashSet<Arc<String>>,
+    banned: HashSet<Arc<String>>,
+    /// Whether the group was made with 'FromClient::Create'.
+    created: bool,
+}
+
+impl Access {
+    fn role(&self, nickname: &String) -> Option<Role> {
+        if self.owner.as_deref() == Some(nickname) {
+            Some(Role::Owner)
+        } else if self.moderators.contains(nickname) {
+            Some(Role::Moderator)
+        } else {
+            None
+        }
+    }
+
+    fn may_moderate(&self, nickname: &String) -> bool {
+        self.role(nickname).is_some()
+    }
 }
 
 impl Group {
-    pub fn new(name: Arc<String>) -> Group {
+    /// Create a group named 'name'. If 'log' is given, restore the group's
+    /// history and access settings from it, and keep logging to it.
+    /// Posts are accepted at no more than 'rate', and relayed through
+    /// 'federation'.
+    ///
+    /// If the log holds messages but the access settings can't be read, the
+    /// history is left on disk unread and nothing more is logged, rather than
+    /// showing a private group's messages to whoever joins first.
+    pub fn new(
+        name: Arc<String>,
+        history_config: HistoryConfig,
+        log: Option<io::Result<OpenedLog>>,
+        rate: Rate,
+        metrics: Arc<Metrics>,
+        federation: Arc<Federation>,
+    ) -> Group {
         let (sender, _receiver) = broadcast::channel(1000);
-        Group { name, sender }
+        let mut history = History::new(history_config);
+        let mut access = Access::default();
+
+        let log = log.and_then(|opened| {
+            let opened = opened.and_then(|opened| {
+                let saved = opened.access.as_deref().map(serde_json::from_slice);
+                Ok((opened.log, opened.messages, saved.transpose()?))
+            });
+
+            match opened {
+                Ok((log, messages, saved)) if saved.is_some() || messages.is_empty() => {
+                    access = saved.unwrap_or_default();
+                    for message in messages {
+                        history.push(message);
+                    }
+                    Some(log)
+                }
+                Ok(_) => {
+                    log!(Error, "group_access_missing", group = name);
+                    None
+                }
+                Err(error) => {
+                    log!(Error, "group_log_open_failed", group = name, error = error);
+                    None
+                }
+            }
+        });
+
+        Group {
+            name,
+            sender,
+            members: Mutex::new(HashMap::new()),
+            history: Mutex::new(history),
+            log,
+            limit: Mutex::new(TokenBucket::new(rate)),
+            access: Mutex::new(access),
+            metrics,
+            federation,
+        }
+    }
+
+    /// Make 'owner' the group's owner, and make the group invite-only if
+    /// 'private' is true. This is for groups made with 'FromClient::Create',
+    /// before anyone has joined.
+    pub fn set_owner(&self, owner: Arc<String>, private: bool) {
+        let mut access = self.access.lock().unwrap();
+
+        access.owner = Some(owner);
+        access.private = private;
+        access.created = true;
+        self.save_access(&access);
+    }
+
+    /// Return 'true' if the group keeps a message log, which 'new' only
+    /// opens once its access settings have been restored.
+    pub fn is_logged(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Subscribe 'outbound' to this group's messages on behalf of 'nickname'.
+    /// The new member is first sent the group's recent history. The first
+    /// user to join a group with no owner becomes its owner.
+    pub fn join(&self, nickname: Arc<String>, outbound: Arc<Outbound>) -> Result<(), String> {
+        let mut access = self.access.lock().unwrap();
+        let mut members = self.members.lock().unwrap();
+
+        if members.contains_key(&nickname) {
+            return Err(format!("Already a member of group '{}'", self.name));
+        }
+        if access.banned.contains(&nickname) {
+            return Err(format!("You are banned from group '{}'", self.name));
+        }
+        if access.private && !access.invited.contains(&nickname) && !access.may_moderate(&nickname)
+        {
+            return Err(format!("Group '{}' is invite-only", self.name));
+        }
+
+        if access.owner.is_none() {
+            access.owner = Some(nickname.clone());
+            self.save_access(&access);
+        }
+
+        // Subscribe and take the snapshot under the history lock, so that
+        // every message ends up either in the replay or on the receiver,
+        // but never both.
+        let (receiver, replay) = {
+            let mut history = self.history.lock().unwrap();
+            (self.sender.subscribe(), history.recent())
+        };
+        let (leave_sender, leave_receiver) = oneshot::channel();
+
+        members.insert(nickname, leave_sender);
+        runtime::spawn(handle_subscriber(
+            self.name.clone(),
+            replay,
+            receiver,
+            leave_receiver,
+            outbound,
+            self.metrics.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Stop delivering this group's messages to 'nickname'.
+    /// Return 'false' if 'nickname' was not a member.
+    pub fn leave(&self, nickname: &String) -> bool {
+        // Dropping the member's 'oneshot::Sender' ends its subscriber task.
+        self.members.lock().unwrap().remove(nickname).is_some()
+    }
+
+    /// Remove every member, returning their nicknames.
+    pub fn close(&self) -> Vec<Arc<String>> {
+        // Dropping the 'oneshot::Sender's ends the subscriber tasks.
+        let members = std::mem::take(&mut *self.members.lock().unwrap());
+        let mut names: Vec<_> = members.into_keys().collect();
+        names.sort();
+        names
+    }
+
+    pub fn summary(&self) -> GroupSummary {
+        let access = self.access.lock().unwrap();
+
+        GroupSummary {
+            name: self.name.clone(),
+            owner: access.owner.clone(),
+            private: access.private,
+            members: self.member_names(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.lock().unwrap().is_empty()
+    }
+
+    /// Return 'true' if the group should be dropped, having no members and
+    /// nothing worth remembering. Groups made with 'FromClient::Create', and
+    /// groups that have banned someone, are kept until the server exits.
+    pub fn is_abandoned(&self) -> bool {
+        let access = self.access.lock().unwrap();
+
+        !access.created && access.banned.is_empty() && self.is_empty()
+    }
+
+    /// Return an error unless 'nickname' may see this group: read its
+    /// history, list its members, and post to it.
+    pub fn check_access(&self, nickname: &String) -> Result<(), String> {
+        let access = self.access.lock().unwrap();
+
+        if access.banned.contains(nickname) {
+            return Err(format!("You are banned from group '{}'", self.name));
+        }
+        if access.private && !self.members.lock().unwrap().contains_key(nickname) {
+            return Err(format!("Not a member of private group '{}'", self.name));
+        }
+
+        Ok(())
+    }
+
+    /// Return 'true' if 'ListGroups' should show this group to 'nickname'.
+    pub fn is_visible_to(&self, nickname: &String) -> bool {
+        let access = self.access.lock().unwrap();
+
+        !access.private
+            || access.invited.contains(nickname)
+            || access.may_moderate(nickname)
+            || self.members.lock().unwrap().contains_key(nickname)
+    }
+
+    /// Let 'nickname' join this group even if it is invite-only, lifting any
+    /// ban. Only moderators may invite.
+    pub fn invite(&self, by: &String, nickname: Arc<String>) -> Result<(), String> {
+        let mut access = self.access.lock().unwrap();
+
+        if !access.may_moderate(by) {
+            return Err(format!("You are not a moderator of group '{}'", self.name));
+        }
+
+        access.banned.remove(&nickname);
+        access.invited.insert(nickname);
+        self.save_access(&access);
+        Ok(())
+    }
+
+    /// Remove 'nickname' from the group on behalf of 'by', and if 'ban' is
+    /// true, keep them from coming back. Moderators may remove members;
+    /// only the owner may remove moderators, and no one may remove the owner.
+    pub fn remove(&self, by: &String, nickname: &Arc<String>, ban: bool) -> Result<(), String> {
+        let mut access = self.access.lock().unwrap();
+
+        let allowed = match (access.role(by), access.role(nickname)) {
+            (Some(Role::Owner), target) => target != Some(Role::Owner),
+            (Some(_), target) => target.is_none(),
+            (None, _) => false,
+        };
+        if !allowed {
+            return Err(format!(
+                "You may not remove '{nickname}' from group '{}'",
+                self.name
+            ));
+        }
+
+        let was_member = self.members.lock().unwrap().remove(nickname).is_some();
+        if !was_member && !ban {
+            return Err(format!(
+                "'{nickname}' is not a member of group '{}'",
+                self.name
+            ));
+        }
+
+        if ban {
+            access.moderators.remove(nickname);
+            access.invited.remove(nickname);
+            access.banned.insert(nickname.clone());
+            self.save_access(&access);
+        }
+
+        Ok(())
+    }
+
+    /// Give 'nickname' the role 'role'. Only the owner may do this, and
+    /// ownership itself can't be handed on.
+    pub fn set_role(&self, by: &String, nickname: Arc<String>, role: Role) -> Result<(), String> {
+        let mut access = self.access.lock().unwrap();
+
+        if access.role(by) != Some(Role::Owner) {
+            return Err(format!("You are not the owner of group '{}'", self.name));
+        }
+        if access.role(&nickname) == Some(Role::Owner) {
+            return Err("The owner's role can't be changed".to_string());
+        }
+
+        match role {
+            Role::Member => {
+                access.moderators.remove(&nickname);
+            }
+            Role::Moderator => {
+                access.moderators.insert(nickname);
+            }
+            Role::Owner => return Err("Ownership can't be transferred".to_string()),
+        }
+
+        self.save_access(&access);
+        Ok(())
+    }
+
+    /// Return the nicknames of this group's members, sorted.
+    pub fn member_names(&self) -> Vec<Arc<String>> {
+        let mut names: Vec<_> = self.members.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Send 'message' to every member. Return an error if the group is
+    /// receiving messages faster than its rate limit allows.
+    pub fn post(&self, sender: Arc<String>, message: Arc<String>) -> Result<(), String> {
+        self.accept(sender, message, None)
+    }
+
+    /// Send 'message' with 'attachment' to every member, as 'post' does.
+    pub fn share(
+        &self,
+        sender: Arc<String>,
+        message: Arc<String>,
+        attachment: Attachment,
+    ) -> Result<(), String> {
+        self.accept(sender, message, Some(attachment))
+    }
+
+    fn accept(
+        &self,
+        sender: Arc<String>,
+        message: Arc<String>,
+        attachment: Option<Attachment>,
+    ) -> Result<(), String> {
+        if !self.limit.lock().unwrap().take() {
+            return Err(format!(
+                "Group '{}' is receiving too many messages; try again shortly",
+                self.name
+            ));
+        }
+
+        let posted = self.publish(sender, message, attachment);
+        self.metrics.messages_posted.fetch_add(1, Ordering::Relaxed);
+        self.federation.relay(&self.name, &posted);
+
+        Ok(())
     }
 
-    pub fn join(&self, outbound: Arc<Outbound>) {
-        let receiver = self.sender.subscribe();
+    /// Send 'message', relayed from another server, to every member, unless
+    /// 'sender' is banned here or the group is private and hasn't invited
+    /// them. The server it was posted on has already applied the group's
+    /// rate limit.
+    pub fn deliver(&self, sender: Arc<String>, message: Arc<String>) -> Result<(), String> {
+        {
+            let access = self.access.lock().unwrap();
 
-        task::spawn(handle_subscriber(self.name.clone(), receiver, outbound));
+            if access.banned.contains(&sender) {
+                return Err(format!("'{sender}' is banned from group '{}'", self.name));
+            }
+            if access.private && !access.invited.contains(&sender) && !access.may_moderate(&sender)
+            {
+                return Err(format!(
+                    "'{sender}' is not invited to group '{}'",
+                    self.name
+                ));
+            }
+        }
+
+        self.publish(sender, message, None);
+        Ok(())
     }
 
-    pub fn post(&self, message: Arc<String>) {
+    /// Add a message to the group's history and log, and send it to every
+    /// member.
+    fn publish(
+        &self,
+        sender: Arc<String>,
+        message: Arc<String>,
+        attachment: Option<Attachment>,
+    ) -> PostedMessage {
+        let mut history = self.history.lock().unwrap();
+
+        // Keep timestamps strictly increasing, even when several messages
+        // arrive in the same millisecond or the system clock steps backwards,
+        // so that a timestamp identifies one message when paging history.
+        let timestamp = history::now_millis().max(history.latest_timestamp() + 1);
+        let posted = PostedMessage {
+            sender,
+            message,
+            timestamp,
+            seq: history.latest_seq() + 1,
+            attachment,
+            edited: false,
+            deleted: false,
+            reactions: Reactions::new(),
+        };
+
+        history.push(posted.clone());
+        self.save(&posted);
+
         // This only returns an error when there are no subscribers.
         // A connection's outgoing side can exit, dropping its subscription,
         // slightly before its incoming side, which may end up trying to send
         // a message to an empty group.
-        let _ignored = self.sender.send(message);
+        let _ignored = self.sender.send(message_packet(&self.name, posted.clone()));
+        posted
+    }
+
+    /// Replace the text of message 'seq' with 'message', on behalf of 'by',
+    /// who must have sent it.
+    pub fn edit(&self, by: &Arc<String>, seq: u64, message: Arc<String>) -> Result<(), String> {
+        self.change(seq, |posted| {
+            if posted.sender != *by {
+                return Err(format!("Only {} may edit message {seq}", posted.sender));
+            }
+
+            posted.message = message.clone();
+            posted.edited = true;
+            Ok(FromServer::Edited {
+                group_name: self.name.clone(),
+                seq,
+                message,
+            })
+        })
+    }
+
+    /// Delete message 'seq' on behalf of 'by', who must have sent it or be a
+    /// moderator.
+    pub fn delete(&self, by: &Arc<String>, seq: u64) -> Result<(), String> {
+        let moderator = self.access.lock().unwrap().may_moderate(by);
+
+        self.change(seq, |posted| {
+            if posted.sender != *by && !moderator {
+                return Err(format!("You may not delete message {seq}"));
+            }
+
+            posted.message = Arc::new(String::new());
+            posted.attachment = None;
+            posted.reactions.clear();
+            posted.deleted = true;
+            Ok(FromServer::Deleted {
+                group_name: self.name.clone(),
+                seq,
+                by: by.clone(),
+            })
+        })
+    }
+
+    /// Add 'by's 'reaction' to message 'seq', or take it back if they've
+    /// already given it.
+    pub fn react(&self, by: &Arc<String>, seq: u64, reaction: Arc<String>) -> Result<(), String> {
+        if reaction.is_empty()
+            || reaction.len() > MAX_REACTION_LENGTH
+            || reaction.contains(char::is_whitespace)
+        {
+            return Err(format!("Invalid reaction '{reaction}'"));
+        }
+
+        self.change(seq, |posted| {
+            let added = toggle_reaction(&mut posted.reactions, &reaction, by);
+            Ok(FromServer::Reacted {
+                group_name: self.name.clone(),
+                seq,
+                nickname: by.clone(),
+                reaction,
+                added,
+            })
+        })
+    }
+
+    /// Apply 'change' to message 'seq' in the history, then save the result
+    /// and send every member the packet 'change' returns. Messages that have
+    /// been deleted, or forgotten, can't be changed.
+    fn change<F>(&self, seq: u64, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut PostedMessage) -> Result<FromServer, String>,
+    {
+        // Holding the history lock throughout keeps changes in the same
+        // order in the history, the log, and the members' streams.
+        let mut history = self.history.lock().unwrap();
+        let posted = match history.get_mut(seq) {
+            Some(posted) if !posted.deleted => posted,
+            Some(_) => return Err(format!("Message {seq} in '{}' was deleted", self.name)),
+            None => return Err(format!("No message {seq} in '{}'", self.name)),
+        };
+
+        let packet = change(posted)?;
+        self.save(posted);
+        let _ignored = self.sender.send(packet);
+        Ok(())
+    }
+
+    /// Append 'posted' to the group's log, if it keeps one.
+    fn save(&self, posted: &PostedMessage) {
+        if let Some(log) = &self.log {
+            log.append(posted);
+        }
+    }
+
+    /// Save 'access' next to the group's log, if it keeps one.
+    fn save_access(&self, access: &Access) {
+        if let Some(log) = &self.log {
+            log.save_access(access);
+        }
+    }
+
+    /// Return up to 'limit' messages from this group's history posted before
+    /// 'before', oldest first.
+    pub fn history(&self, before: Option<u64>, limit: usize) -> Vec<PostedMessage> {
+        self.history.lock().unwrap().before(before, limit)
     }
 }
 
 async fn handle_subscriber(
     group_name: Arc<String>,
-    mut receiver: broadcast::Receiver<Arc<String>>,
+    replay: Vec<PostedMessage>,
+    mut receiver: broadcast::Receiver<FromServer>,
+    mut leave: oneshot::Receiver<()>,
     outbound: Arc<Outbound>,
+    metrics: Arc<Metrics>,
 ) {
+    for posted in replay {
+        let packet = message_packet(&group_name, posted);
+        if outbound.send(packet).is_err() {
+            return;
+        }
+    }
+
+    let end = FromServer::HistoryEnd {
+        group_name: group_name.clone(),
+    };
+    if outbound.send(end).is_err() {
+        return;
+    }
+
     loop {
-        let packet = match receiver.recv().await {
-            Ok(message) => FromServer::Message {
-                group_name: group_name.clone(),
-                message: message.clone(),
-            },
-            Err(RecvError::Lagged(n)) => {
+        let received = async { Some(receiver.recv().await) };
+        let left = async {
+            let _ = (&mut leave).await;
+            None
+        };
+
+        let packet = match received.race(left).await {
+            Some(Ok(packet)) => packet,
+            Some(Err(RecvError::Lagged(n))) => {
+                metrics.messages_lagged.fetch_add(n, Ordering::Relaxed);
                 FromServer::Error(format!("Dropped {n} messages from {group_name}."))
             }
-            Err(RecvError::Closed) => break,
+            Some(Err(RecvError::Closed)) | None => break,
         };
 
-        if outbound.send(packet).await.is_err() {
+        if outbound.send(packet).is_err() {
             break;
         }
     }
 }
+
+fn message_packet(group_name: &Arc<String>, posted: PostedMessage) -> FromServer {
+    FromServer::Message {
+        group_name: group_name.clone(),
+        sender: posted.sender,
+        message: posted.message,
+        timestamp: posted.timestamp,
+        seq: posted.seq,
+        attachment: posted.attachment,
+        edited: posted.edited,
+        deleted: posted.deleted,
+        reactions: posted.reactions,
+    }
+}
+
+/// Add 'nickname' to those who reacted with 'reaction', returning true, or
+/// remove them if they already had, returning false.
+fn toggle_reaction(
+    reactions: &mut Reactions,
+    reaction: &Arc<String>,
+    nickname: &Arc<String>,
+) -> bool {
+    let nicknames = reactions.entry(reaction.clone()).or_default();
+
+    if let Some(position) = nicknames.iter().position(|n| n == nickname) {
+        nicknames.remove(position);
+        if nicknames.is_empty() {
+            reactions.remove(reaction);
+        }
+        false
+    } else {
+        nicknames.push(nickname.clone());
+        true
+    }
+}
+
+#[test]
+fn test_group_permissions() {
+    use crate::harness::name;
+    use crate::outbound::OutboundConfig;
+    use crate::rate_limit::RateLimitConfig;
+    use async_chat::utils::ChatError;
+    use futures::sink::{self, SinkExt};
+
+    let outbound = || {
+        let discard = sink::drain().sink_map_err(|never| -> ChatError { match never {} });
+        Arc::new(Outbound::from_sink(
+            discard,
+            OutboundConfig::default(),
+            Arc::new(Metrics::new()),
+        ))
+    };
+
+    let group = Group::new(
+        name("Dogs"),
+        HistoryConfig::default(),
+        None,
+        RateLimitConfig::default().group,
+        Arc::new(Metrics::new()),
+        Arc::new(Federation::new(None, Arc::new(Metrics::new())).unwrap()),
+    );
+    group.set_owner(name("owner"), true);
+
+    // Only invitees and moderators may join a private group.
+    assert!(group.join(name("owner"), outbound()).is_ok());
+    assert!(group.join(name("fido"), outbound()).is_err());
+    assert!(group.invite(&name("fido"), name("rex")).is_err());
+    group.invite(&name("owner"), name("fido")).unwrap();
+    group.join(name("fido"), outbound()).unwrap();
+    assert!(group.check_access(&name("fido")).is_ok());
+    assert!(group.check_access(&name("rex")).is_err());
+
+    // Moderators may remove members, but not each other or the owner.
+    group
+        .set_role(&name("owner"), name("mod"), Role::Moderator)
+        .unwrap();
+    group.join(name("mod"), outbound()).unwrap();
+    assert!(group
+        .set_role(&name("mod"), name("fido"), Role::Moderator)
+        .is_err());
+    assert!(group.remove(&name("mod"), &name("owner"), false).is_err());
+    group.remove(&name("mod"), &name("fido"), true).unwrap();
+    assert_eq!(group.member_names(), vec![name("mod"), name("owner")]);
+
+    // A ban keeps someone out until they are invited back.
+    assert!(group.join(name("fido"), outbound()).is_err());
+    assert!(!group.is_abandoned());
+    group.invite(&name("mod"), name("fido")).unwrap();
+    group.join(name("fido"), outbound()).unwrap();
+}
+
+#[test]
+fn test_lagging_subscriber() {
+    use crate::outbound::OutboundConfig;
+    use async_chat::protocol;
+    use async_chat::utils::ChatError;
+    use futures::sink;
+
+    runtime::block_on(async {
+        // Clients are told what they missed whatever they negotiated, including
+        // those that track sequence numbers themselves.
+        for capabilities in [
+            vec![protocol::HISTORY],
+            vec![protocol::HISTORY, protocol::ACKS],
+        ] {
+            let capabilities: Vec<_> = capabilities.into_iter().map(str::to_string).collect();
+            let received = Arc::new(Mutex::new(Vec::new()));
+            let collect = sink::unfold(received.clone(), |received, packet| async move {
+                received.lock().unwrap().push(packet);
+                Ok::<_, ChatError>(received)
+            });
+            let metrics = Arc::new(Metrics::new());
+            let outbound = Arc::new(Outbound::from_sink(
+                collect,
+                OutboundConfig::default(),
+                metrics.clone(),
+            ));
+            outbound.set_agreement(protocol::negotiate(protocol::VERSION, &capabilities).unwrap());
+
+            // Five messages go out on a channel with room for two before the
+            // subscriber gets to them, so it misses the first three.
+            let dogs = Arc::new("Dogs".to_string());
+            let (sender, receiver) = broadcast::channel(2);
+            for i in 0..5 {
+                sender.send(FromServer::Error(i.to_string())).unwrap();
+            }
+            drop(sender);
+
+            let (_leave, leave_receiver) = oneshot::channel();
+            handle_subscriber(
+                dogs.clone(),
+                Vec::new(),
+                receiver,
+                leave_receiver,
+                outbound.clone(),
+                metrics.clone(),
+            )
+            .await;
+            outbound.finish().await;
+
+            let packet = |text: &str| FromServer::Error(text.to_string());
+            assert_eq!(
+                *received.lock().unwrap(),
+                [
+                    FromServer::HistoryEnd { group_name: dogs },
+                    packet("Dropped 3 messages from Dogs."),
+                    packet("3"),
+                    packet("4"),
+                ]
+            );
+            assert_eq!(metrics.messages_lagged.load(Ordering::Relaxed), 3);
+        }
+    });
+}
+
+#[test]
+fn test_message_changes() {
+    use crate::harness::{name, next_message, next_packet, scratch_dir, TestServer};
+    use async_chat::client::ChatClient;
+    use async_chat::FromClient;
+
+    let directory = scratch_dir("changes");
+    let log_dir = directory.to_str().unwrap();
+
+    let edit = |seq, message: &str| FromClient::Edit {
+        group_name: name("Dogs"),
+        seq,
+        message: name(message),
+    };
+    let delete = |seq| FromClient::Delete {
+        group_name: name("Dogs"),
+        seq,
+    };
+    let react = |seq, reaction: &str| FromClient::React {
+        group_name: name("Dogs"),
+        seq,
+        reaction: name(reaction),
+    };
+
+    // Return the next packet that isn't a new message or an ack.
+    async fn next_change(client: &mut ChatClient) -> FromServer {
+        loop {
+            match next_packet(client).await.unwrap() {
+                FromServer::Message { .. } | FromServer::Ack { .. } => {}
+                packet => return packet,
+            }
+        }
+    }
+
+    runtime::block_on(async {
+        let server = TestServer::start(&["--log-dir", log_dir]).await;
+        let mut fido = server.member("fido", "Dogs").await;
+        let mut rex = server.member("rex", "Dogs").await;
+        for (poster, text) in [(1, "arf"), (0, "woof")] {
+            let clients = [&mut fido, &mut rex];
+            clients[poster].post("Dogs", text).await.unwrap();
+            for client in clients {
+                next_message(client).await;
+            }
+        }
+
+        // Only the sender may edit a message, and everyone hears of it.
+        fido.send(&edit(1, "meow")).await.unwrap();
+        assert_eq!(
+            next_change(&mut fido).await,
+            FromServer::Error("Only rex may edit message 1".to_string())
+        );
+        rex.send(&edit(1, "arf arf")).await.unwrap();
+        let edited = FromServer::Edited {
+            group_name: name("Dogs"),
+            seq: 1,
+            message: name("arf arf"),
+        };
+        assert_eq!(next_change(&mut fido).await, edited);
+        assert_eq!(next_change(&mut rex).await, edited);
+
+        // Reacting again takes the reaction back.
+        for added in [true, false, true] {
+            fido.send(&react(2, "+1")).await.unwrap();
+            assert_eq!(
+                next_change(&mut rex).await,
+                FromServer::Reacted {
+                    group_name: name("Dogs"),
+                    seq: 2,
+                    nickname: name("fido"),
+                    reaction: name("+1"),
+                    added,
+                }
+            );
+        }
+
+        // Moderators may delete anyone's messages, and members only their
+        // own. Deleted messages can't be changed.
+        rex.send(&delete(2)).await.unwrap();
+        assert_eq!(
+            next_change(&mut rex).await,
+            FromServer::Error("You may not delete message 2".to_string())
+        );
+        fido.send(&delete(1)).await.unwrap();
+        assert_eq!(
+            next_change(&mut rex).await,
+            FromServer::Deleted {
+                group_name: name("Dogs"),
+                seq: 1,
+                by: name("fido"),
+            }
+        );
+        rex.send(&react(1, "+1")).await.unwrap();
+        assert_eq!(
+            next_change(&mut rex).await,
+            FromServer::Error("Message 1 in 'Dogs' was deleted".to_string())
+        );
+        rex.send(&react(3, "+1")).await.unwrap();
+        assert_eq!(
+            next_change(&mut rex).await,
+            FromServer::Error("No message 3 in 'Dogs'".to_string())
+        );
+        server.shutdown().await;
+
+        // The changes are in the history, even after a restart.
+        let server = TestServer::start(&["--log-dir", log_dir]).await;
+        let mut spot = server.client("spot").await;
+        spot.join("Dogs").await.unwrap();
+        let mut replay = Vec::new();
+        while let Some(FromServer::Message {
+            seq,
+            message,
+            deleted,
+            reactions,
+            ..
+        }) = next_packet(&mut spot).await
+        {
+            replay.push((seq, message, deleted, reactions));
+        }
+        let reactions = Reactions::from([(name("+1"), vec![name("fido")])]);
+        assert_eq!(
+            replay,
+            [
+                (1, name(""), true, Reactions::new()),
+                (2, name("woof"), false, reactions)
+            ]
+        );
+    });
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -1,24 +1,307 @@
-use crate::group::Group;
+use crate::federation::Federation;
+use crate::group::{Group, GroupSummary};
+use crate::history::HistoryConfig;
+use crate::message_log::{LogConfig, LogStore};
+use crate::metrics::Metrics;
+use crate::outbound::Outbound;
+use crate::rate_limit::Rate;
 use std::collections::HashMap;
+use std::io;
 use std::sync::{Arc, Mutex};
 
-pub struct GroupTable(Mutex<HashMap<Arc<String>, Arc<Group>>>);
+pub struct GroupTable {
+    groups: Mutex<HashMap<Arc<String>, Arc<Group>>>,
+    history_config: HistoryConfig,
+    logs: Option<LogStore>,
+    post_rate: Rate,
+    metrics: Arc<Metrics>,
+    federation: Arc<Federation>,
+}
 
 impl GroupTable {
-    pub fn new() -> GroupTable {
-        GroupTable(Mutex::new(HashMap::new()))
+    pub fn new(
+        history_config: HistoryConfig,
+        log_config: Option<LogConfig>,
+        post_rate: Rate,
+        metrics: Arc<Metrics>,
+        federation: Arc<Federation>,
+    ) -> GroupTable {
+        GroupTable {
+            groups: Mutex::new(HashMap::new()),
+            history_config,
+            logs: log_config.map(LogStore::start),
+            post_rate,
+            metrics,
+            federation,
+        }
+    }
+
+    /// Recreate every group that has a message log on disk, along with its
+    /// history and access settings. Groups whose access settings can't be
+    /// restored are skipped. Does nothing if the server isn't keeping logs.
+    pub async fn restore(&self) -> io::Result<()> {
+        let logs = match &self.logs {
+            Some(logs) => logs,
+            None => return Ok(()),
+        };
+
+        for name in logs.logged_groups()? {
+            let name = Arc::new(name);
+            let group = self.load_group(name.clone()).await;
+            if group.is_logged() {
+                self.groups.lock().unwrap().insert(name, group);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make a new group named 'name', restored from its message log if the
+    /// server keeps them. This waits on the disk, so it's done without the
+    /// table locked.
+    async fn load_group(&self, name: Arc<String>) -> Arc<Group> {
+        let log = match &self.logs {
+            Some(logs) => Some(logs.open(name.clone()).await),
+            None => None,
+        };
+
+        Arc::new(Group::new(
+            name,
+            self.history_config,
+            log,
+            self.post_rate,
+            self.metrics.clone(),
+            self.federation.clone(),
+        ))
     }
 
     pub fn get(&self, name: &String) -> Option<Arc<Group>> {
-        self.0.lock().unwrap().get(name).cloned()
+        self.groups.lock().unwrap().get(name).cloned()
+    }
+
+    /// Add 'nickname' to the group named 'name', creating the group if needed.
+    ///
+    /// Membership changes happen with the table locked, so a group can't be
+    /// dropped for being empty while someone is joining it. If the group has
+    /// to be loaded, that happens first, and the table is checked again
+    /// afterwards in case someone else got there in the meantime.
+    pub async fn join(
+        &self,
+        name: Arc<String>,
+        nickname: Arc<String>,
+        outbound: Arc<Outbound>,
+    ) -> Result<(), String> {
+        let mut loaded = None;
+        loop {
+            {
+                let mut groups = self.groups.lock().unwrap();
+                let existing = groups.get(&name).cloned();
+                if let Some(group) = existing.or_else(|| loaded.take()) {
+                    groups.insert(name.clone(), group.clone());
+
+                    let result = group.join(nickname, outbound);
+                    if group.is_abandoned() {
+                        groups.remove(&name);
+                    }
+                    return result;
+                }
+            }
+
+            loaded = Some(self.load_group(name.clone()).await);
+        }
+    }
+
+    /// Create a group named 'name' owned by 'owner', who joins it at once.
+    /// If 'private' is true, the group is invite-only.
+    pub async fn create(
+        &self,
+        name: Arc<String>,
+        owner: Arc<String>,
+        private: bool,
+        outbound: Arc<Outbound>,
+    ) -> Result<(), String> {
+        let exists = || format!("Group '{name}' already exists");
+        if self.groups.lock().unwrap().contains_key(&name) {
+            return Err(exists());
+        }
+
+        let group = self.load_group(name.clone()).await;
+        let mut groups = self.groups.lock().unwrap();
+        if groups.contains_key(&name) {
+            return Err(exists());
+        }
+
+        group.set_owner(owner.clone(), private);
+        group.join(owner, outbound)?;
+        groups.insert(name, group);
+
+        Ok(())
+    }
+
+    /// Remove 'nickname' from the group named 'name' on behalf of 'by', as
+    /// for 'Group::remove', dropping the group if it is left abandoned.
+    pub fn remove(
+        &self,
+        name: &String,
+        by: &String,
+        nickname: &Arc<String>,
+        ban: bool,
+    ) -> Result<(), String> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups
+            .get(name)
+            .ok_or_else(|| format!("Group '{name}' does not exist"))?;
+
+        group.remove(by, nickname, ban)?;
+        if group.is_abandoned() {
+            groups.remove(name);
+        }
+
+        Ok(())
     }
 
-    pub fn get_or_create(&self, name: Arc<String>) -> Arc<Group> {
-        self.0
-            .lock()
-            .unwrap()
-            .entry(name.clone())
-            .or_insert_with(|| Arc::new(Group::new(name)))
-            .clone()
+    /// Remove 'nickname' from the group named 'name', dropping the group if
+    /// it is left abandoned. Return 'false' if 'nickname' was not a member.
+    ///
+    /// A dropped group's message log stays on disk, so its history comes back
+    /// if the group is joined again.
+    pub fn leave(&self, name: &String, nickname: &String) -> bool {
+        let mut groups = self.groups.lock().unwrap();
+        let group = match groups.get(name) {
+            Some(group) => group,
+            None => return false,
+        };
+
+        let left = group.leave(nickname);
+        if group.is_abandoned() {
+            groups.remove(name);
+        }
+
+        left
+    }
+
+    /// Remove 'nickname' from every group, as when its connection closes.
+    pub fn leave_all(&self, nickname: &String) {
+        self.groups.lock().unwrap().retain(|_name, group| {
+            group.leave(nickname);
+            !group.is_abandoned()
+        });
+    }
+
+    /// Drop the group named 'name', removing all its members, whose
+    /// nicknames are returned. As with abandoned groups, its message log
+    /// stays on disk.
+    pub fn close(&self, name: &String) -> Option<Vec<Arc<String>>> {
+        let group = self.groups.lock().unwrap().remove(name)?;
+        Some(group.close())
     }
+
+    /// Wait until every message logged so far has reached the disk, if the
+    /// server keeps logs.
+    pub async fn sync_logs(&self) {
+        if let Some(logs) = &self.logs {
+            logs.sync().await;
+        }
+    }
+
+    /// Return the names of the groups 'nickname' can see, sorted.
+    pub fn names(&self, nickname: &String) -> Vec<Arc<String>> {
+        let groups = self.groups.lock().unwrap();
+        let mut names: Vec<_> = groups
+            .iter()
+            .filter(|(_name, group)| group.is_visible_to(nickname))
+            .map(|(name, _group)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Describe every group, sorted by name.
+    pub fn summaries(&self) -> Vec<GroupSummary> {
+        let groups = self.groups.lock().unwrap();
+        let mut summaries: Vec<_> = groups.values().map(|group| group.summary()).collect();
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.lock().unwrap().len()
+    }
+}
+
+#[test]
+fn test_restored_access() {
+    use crate::harness::{join, name, next_message, next_packet, scratch_dir, TestServer};
+    use async_chat::{runtime, FromClient, FromServer};
+
+    let directory = scratch_dir("access");
+    let log_dir = directory.to_str().unwrap();
+
+    runtime::block_on(async {
+        // The owner makes a private group and bans rex from a public one.
+        let server = TestServer::start(&["--log-dir", log_dir]).await;
+        let mut owner = server.client("owner").await;
+        owner
+            .send(&FromClient::Create {
+                group_name: name("Secrets"),
+                private: true,
+            })
+            .await
+            .unwrap();
+        owner.post("Secrets", "swordfish").await.unwrap();
+        assert_eq!(next_message(&mut owner).await, "owner: swordfish");
+        join(&mut owner, "Dogs").await;
+        owner
+            .send(&FromClient::Ban {
+                group_name: name("Dogs"),
+                nickname: name("rex"),
+            })
+            .await
+            .unwrap();
+        owner.post("Dogs", "no rex").await.unwrap();
+        assert_eq!(next_message(&mut owner).await, "owner: no rex");
+        server.shutdown().await;
+
+        // After a restart, outsiders still can't read or join the private
+        // group, rex is still banned, and the owner is still the owner.
+        let server = TestServer::start(&["--log-dir", log_dir]).await;
+        let mut spot = server.client("spot").await;
+        spot.send(&FromClient::History {
+            group_name: name("Secrets"),
+            before: None,
+            limit: None,
+        })
+        .await
+        .unwrap();
+        spot.join("Secrets").await.unwrap();
+        let mut rex = server.client("rex").await;
+        rex.join("Dogs").await.unwrap();
+        assert_eq!(
+            [
+                next_packet(&mut spot).await,
+                next_packet(&mut spot).await,
+                next_packet(&mut rex).await
+            ],
+            [
+                Some(FromServer::Error(
+                    "Not a member of private group 'Secrets'".to_string()
+                )),
+                Some(FromServer::Error(
+                    "Group 'Secrets' is invite-only".to_string()
+                )),
+                Some(FromServer::Error(
+                    "You are banned from group 'Dogs'".to_string()
+                )),
+            ]
+        );
+
+        let mut owner = server.client("owner").await;
+        owner.join("Secrets").await.unwrap();
+        assert_eq!(next_message(&mut owner).await, "owner: swordfish");
+        let secrets = server.state.groups.get(&"Secrets".to_string()).unwrap();
+        assert_eq!(secrets.summary().owner, Some(name("owner")));
+        server.shutdown().await;
+    });
+
+    std::fs::remove_dir_all(&directory).unwrap();
 }
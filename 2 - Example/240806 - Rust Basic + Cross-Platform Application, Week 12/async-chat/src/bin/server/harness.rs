@@ -0,0 +1,332 @@
+//! Running a whole server inside a test.
+//!
+//! 'TestServer::start' serves on an ephemeral localhost port, through the
+//! same accept loop as 'main', on the test's own runtime. Tests then connect
+//! as many 'ChatClient's as they like and script them, while still being able
+//! to look at the server's state directly.
+
+use async_chat::client::ChatClient;
+use async_chat::runtime::{self, JoinHandle, TcpListener};
+use async_chat::FromServer;
+use futures::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::accept_connections;
+use crate::config::ServerConfig;
+use crate::connection::serve;
+use crate::state::ServerState;
+
+/// How long a test waits for anything before giving up, so that a broken
+/// server fails the test instead of hanging it.
+const PATIENCE: Duration = Duration::from_secs(10);
+
+pub struct TestServer {
+    pub state: Arc<ServerState>,
+    /// The address clients connect to.
+    pub address: String,
+    accepting: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Start a server configured by the command-line options 'options'.
+    pub async fn start(options: &[&str]) -> TestServer {
+        let args = ["127.0.0.1:0"]
+            .iter()
+            .chain(options)
+            .map(|arg| arg.to_string());
+        let config = ServerConfig::from_args(args).unwrap();
+        let state = Arc::new(ServerState::new(&config).unwrap());
+        state.groups.restore().await.unwrap();
+
+        let listener = TcpListener::bind(&config.address).await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let serve_state = state.clone();
+        let accepting = runtime::spawn(accept_connections(
+            listener,
+            None,
+            state.shutdown.clone(),
+            move |stream, peer| serve(stream, peer, serve_state.clone()),
+        ));
+
+        TestServer {
+            state,
+            address,
+            accepting,
+        }
+    }
+
+    /// Connect a client, logged in as 'nickname'.
+    pub async fn client(&self, nickname: &str) -> ChatClient {
+        let mut client = ChatClient::connect(&self.address).await.unwrap();
+        client.login(nickname, None).await.unwrap();
+        client
+    }
+
+    /// Connect a client logged in as 'nickname', and have it join
+    /// 'group_name'.
+    pub async fn member(&self, nickname: &str, group_name: &str) -> ChatClient {
+        let mut client = self.client(nickname).await;
+        join(&mut client, group_name).await;
+        client
+    }
+
+    /// Shut the server down as Control-C would, and wait for every
+    /// connection to close.
+    pub async fn shutdown(self) {
+        self.state.shutdown.trigger();
+        self.accepting.await;
+        within(self.state.shutdown.idle()).await;
+        within(self.state.groups.sync_logs()).await;
+    }
+}
+
+/// Return 'text' as the shared string that names and messages are passed as.
+pub fn name(text: &str) -> Arc<String> {
+    Arc::new(text.to_string())
+}
+
+/// Return an empty directory for a test to keep files in. 'label' and the
+/// process id keep tests, and test runs, out of each other's way.
+pub fn scratch_dir(label: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("async-chat-{label}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    directory
+}
+
+/// Wait for 'future', failing the test if it takes too long.
+pub async fn within<F: Future>(future: F) -> F::Output {
+    runtime::timeout(PATIENCE, future)
+        .await
+        .expect("timed out waiting for the server")
+}
+
+/// Return the next packet from the server, or 'None' if it closed the
+/// connection.
+pub async fn next_packet(client: &mut ChatClient) -> Option<FromServer> {
+    within(client.next()).await.map(Result::unwrap)
+}
+
+/// Join 'group_name', skipping past its history.
+pub async fn join(client: &mut ChatClient, group_name: &str) {
+    client.join(group_name).await.unwrap();
+    loop {
+        match next_packet(client).await {
+            Some(FromServer::HistoryEnd { .. }) => return,
+            Some(_) => {}
+            None => panic!("disconnected while joining '{group_name}'"),
+        }
+    }
+}
+
+/// Return the next message posted to any of the client's groups, as
+/// "sender: message", skipping anything else.
+pub async fn next_message(client: &mut ChatClient) -> String {
+    loop {
+        match next_packet(client).await {
+            Some(FromServer::Message {
+                sender, message, ..
+            }) => return format!("{sender}: {message}"),
+            Some(_) => {}
+            None => panic!("disconnected while waiting for a message"),
+        }
+    }
+}
+
+/// Wait until 'condition' holds, checking now and then.
+pub async fn eventually(condition: impl Fn() -> bool) {
+    within(async {
+        while !condition() {
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+}
+
+#[test]
+fn test_end_to_end() {
+    use async_chat::FromClient;
+    use std::sync::atomic::Ordering;
+
+    runtime::block_on(async {
+        let server = TestServer::start(&["--post-burst", "100"]).await;
+        let mut fido = server.member("fido", "Dogs").await;
+        let mut rex = server.member("rex", "Dogs").await;
+        let mut spot = server.member("spot", "Dogs").await;
+
+        // Posts from several clients at once reach every member in the same
+        // order, numbered without gaps, with each sender's posts in the
+        // order they were sent.
+        let mut posters = Vec::new();
+        for client in [&mut fido, &mut rex, &mut spot] {
+            posters.push(async move {
+                for i in 0..20 {
+                    client.post("Dogs", &i.to_string()).await.unwrap();
+                }
+            });
+        }
+        future::join_all(posters).await;
+
+        let mut heard = Vec::new();
+        for client in [&mut fido, &mut rex, &mut spot] {
+            let mut messages = Vec::new();
+            while messages.len() < 60 {
+                if let Some(FromServer::Message {
+                    sender,
+                    message,
+                    seq,
+                    ..
+                }) = next_packet(client).await
+                {
+                    assert_eq!(seq, messages.len() as u64 + 1);
+                    messages.push((sender, message.parse::<u32>().unwrap()));
+                }
+            }
+            heard.push(messages);
+        }
+        assert_eq!(heard[0], heard[1]);
+        assert_eq!(heard[0], heard[2]);
+        for nickname in ["fido", "rex", "spot"] {
+            let sent: Vec<u32> = heard[0]
+                .iter()
+                .filter(|(sender, _)| **sender == nickname)
+                .map(|&(_, i)| i)
+                .collect();
+            assert_eq!(sent, (0..20).collect::<Vec<_>>());
+        }
+
+        // Requests naming a group that doesn't exist are refused. Acks for
+        // the posts above may still be on their way, so skip them.
+        let id = fido.post("Cats", "meow").await.unwrap();
+        fido.send(&FromClient::History {
+            group_name: Arc::new("Cats".to_string()),
+            before: None,
+            limit: None,
+        })
+        .await
+        .unwrap();
+        let mut replies = Vec::new();
+        while replies.len() < 2 {
+            match next_packet(&mut fido).await {
+                Some(FromServer::Ack { .. }) => {}
+                reply => replies.push(reply.unwrap()),
+            }
+        }
+        let missing = "Group 'Cats' does not exist".to_string();
+        assert_eq!(
+            replies,
+            [
+                FromServer::Rejected {
+                    id,
+                    reason: missing.clone()
+                },
+                FromServer::Error(missing)
+            ]
+        );
+
+        // A client that hangs up is logged out and leaves its groups, and
+        // its nickname is free again.
+        drop(rex);
+        let rex_name = "rex".to_string();
+        eventually(|| server.state.users.get(&rex_name).is_none()).await;
+        let dogs = server.state.groups.get(&"Dogs".to_string()).unwrap();
+        assert!(!dogs.member_names().contains(&Arc::new(rex_name.clone())));
+        spot.post("Dogs", "still here").await.unwrap();
+        assert_eq!(next_message(&mut fido).await, "spot: still here");
+        let rex = server.client("rex").await;
+
+        // Shutting down tells everyone why, then closes every connection.
+        let metrics = server.state.metrics.clone();
+        server.shutdown().await;
+        for mut client in [fido, rex, spot] {
+            let mut packets = Vec::new();
+            while let Some(packet) = next_packet(&mut client).await {
+                packets.push(packet);
+            }
+            let farewell = FromServer::Error("The server is shutting down".to_string());
+            assert_eq!(packets.last(), Some(&farewell));
+        }
+        assert_eq!(metrics.connections_opened.load(Ordering::Relaxed), 4);
+    });
+}
+
+#[test]
+fn test_lagging_clients() {
+    use async_chat::client::ClientOptions;
+
+    runtime::block_on(async {
+        let server = TestServer::start(&[
+            "--outbound-queue",
+            "8",
+            "--post-rate",
+            "100000",
+            "--post-burst",
+            "100000",
+            "--group-post-rate",
+            "100000",
+            "--group-post-burst",
+            "100000",
+            "--max-message-length",
+            "65536",
+        ])
+        .await;
+        let mut laggard = server.member("laggard", "Dogs").await;
+
+        // Fido neither joins nor asks for acks, so nothing is sent back to
+        // it, and any packets the server drops are the laggard's.
+        let options = ClientOptions {
+            capabilities: Vec::new(),
+            ..ClientOptions::default()
+        };
+        let mut fido = ChatClient::connect_with(&server.address, &options)
+            .await
+            .unwrap();
+        fido.login("fido", None).await.unwrap();
+
+        // While the laggard reads nothing, fido posts until the server has
+        // had to drop something meant for it. The socket buffers soak up a
+        // good deal first, so the posts are large.
+        let text = "woof ".repeat(6000);
+        let metrics = server.state.metrics.clone();
+        let dropped = || {
+            metrics
+                .packets_dropped
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+        while dropped() == 0 {
+            fido.post("Dogs", &text).await.unwrap();
+        }
+
+        // The laggard is told what it missed, and what it does get is still
+        // in order.
+        let mut last_seq = 0;
+        let notice = loop {
+            match next_packet(&mut laggard).await {
+                Some(FromServer::Message { seq, .. }) => {
+                    assert!(seq > last_seq);
+                    last_seq = seq;
+                }
+                Some(FromServer::Error(notice)) => break notice,
+                Some(_) => {}
+                None => panic!("the laggard was disconnected"),
+            }
+        };
+        assert!(notice.starts_with("Dropped "), "{notice}");
+        assert!(notice.ends_with("because the connection fell behind."));
+
+        // Once it catches up, it hears new posts as usual.
+        fido.post("Dogs", "caught up?").await.unwrap();
+        loop {
+            if let Some(FromServer::Message { seq, message, .. }) = next_packet(&mut laggard).await
+            {
+                assert!(seq > last_seq);
+                last_seq = seq;
+                if *message == "caught up?" {
+                    break;
+                }
+            }
+        }
+    });
+}
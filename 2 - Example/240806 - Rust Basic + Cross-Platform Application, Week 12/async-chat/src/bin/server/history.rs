@@ -0,0 +1,160 @@
+use async_chat::PostedMessage;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How much of a group's past messages to keep around.
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryConfig {
+    pub max_messages: usize,
+    /// Messages older than this are forgotten. 'None' keeps them until
+    /// 'max_messages' pushes them out.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            max_messages: 100,
+            max_age: None,
+        }
+    }
+}
+
+/// A bounded ring of a group's most recent messages, oldest first.
+pub struct History {
+    config: HistoryConfig,
+    messages: VecDeque<PostedMessage>,
+    /// The highest sequence number pushed so far, even if that message has
+    /// since been forgotten.
+    latest_seq: u64,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> History {
+        History {
+            config,
+            messages: VecDeque::with_capacity(config.max_messages),
+            latest_seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, message: PostedMessage) {
+        self.latest_seq = self.latest_seq.max(message.seq);
+        if self.config.max_messages == 0 {
+            return;
+        }
+
+        while self.messages.len() >= self.config.max_messages {
+            self.messages.pop_front();
+        }
+
+        self.messages.push_back(message);
+    }
+
+    /// Return the timestamp of the newest message, or zero if there is none.
+    pub fn latest_timestamp(&self) -> u64 {
+        self.messages.back().map_or(0, |m| m.timestamp)
+    }
+
+    /// Return the sequence number of the newest message, or zero if there
+    /// has been none.
+    pub fn latest_seq(&self) -> u64 {
+        self.latest_seq
+    }
+
+    /// Return every message still in the history, oldest first.
+    pub fn recent(&mut self) -> Vec<PostedMessage> {
+        self.expire(now_millis());
+        self.messages.iter().cloned().collect()
+    }
+
+    /// Return up to 'limit' of the newest messages posted strictly before
+    /// 'before' (or all of them, if 'None'), oldest first.
+    pub fn before(&mut self, before: Option<u64>, limit: usize) -> Vec<PostedMessage> {
+        self.expire(now_millis());
+
+        let end = match before {
+            Some(before) => self.messages.partition_point(|m| m.timestamp < before),
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(limit);
+
+        self.messages.range(start..end).cloned().collect()
+    }
+
+    /// Return the message numbered 'seq', if it is still in the history.
+    pub fn get_mut(&mut self, seq: u64) -> Option<&mut PostedMessage> {
+        self.expire(now_millis());
+
+        // Messages are kept in the order they were numbered, with any saved
+        // before numbering began, as zero, at the front.
+        let index = self.messages.partition_point(|m| m.seq < seq);
+        self.messages
+            .get_mut(index)
+            .filter(|m| m.seq == seq && seq != 0)
+    }
+
+    /// Drop messages older than 'max_age', as of the time 'now'.
+    fn expire(&mut self, now: u64) {
+        let max_age = match self.config.max_age {
+            Some(max_age) => max_age.as_millis() as u64,
+            None => return,
+        };
+
+        while let Some(oldest) = self.messages.front() {
+            if now.saturating_sub(oldest.timestamp) <= max_age {
+                break;
+            }
+            self.messages.pop_front();
+        }
+    }
+}
+
+/// Return the current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[test]
+fn test_history_paging() {
+    use async_chat::Reactions;
+    use std::sync::Arc;
+
+    let config = HistoryConfig {
+        max_messages: 3,
+        max_age: None,
+    };
+    let mut history = History::new(config);
+
+    for timestamp in 1..=4 {
+        history.push(PostedMessage {
+            sender: Arc::new("jimb".to_string()),
+            message: Arc::new(format!("message {timestamp}")),
+            timestamp,
+            seq: timestamp,
+            attachment: None,
+            edited: false,
+            deleted: false,
+            reactions: Reactions::new(),
+        });
+    }
+    // The sequence continues past messages that have been forgotten.
+    assert_eq!(history.latest_seq(), 4);
+
+    let timestamps = |messages: Vec<PostedMessage>| -> Vec<u64> {
+        messages.iter().map(|m| m.timestamp).collect()
+    };
+
+    assert_eq!(timestamps(history.recent()), vec![2, 3, 4]);
+    assert_eq!(timestamps(history.before(None, 2)), vec![3, 4]);
+    assert_eq!(timestamps(history.before(Some(4), 1)), vec![3]);
+    assert!(history.before(Some(2), 10).is_empty());
+
+    // Only messages still held can be looked up.
+    assert_eq!(history.get_mut(3).unwrap().timestamp, 3);
+    assert!(history.get_mut(1).is_none());
+    assert!(history.get_mut(5).is_none());
+}
@@ -0,0 +1,137 @@
+//! Structured logging.
+//!
+//! Each event is written to the standard error as one line of 'key=value'
+//! pairs, in the format known as logfmt:
+//!
+//! ```text
+//! time=2024-08-06T09:15:02.417Z level=warn event=connection_error peer=127.0.0.1:53114 error="Too many failed logins as 'jimb'"
+//! ```
+//!
+//! so the log can be searched and filtered by field. Values containing spaces,
+//! quotes or '=' are quoted.
+
+use std::fmt::{Display, Write as _};
+
+use crate::history;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// Log 'event' at the given level, with any number of 'key = value' fields:
+///
+/// ```ignore
+/// log!(Warn, "connection_error", peer = peer, error = error);
+/// ```
+macro_rules! log {
+    ($level:ident, $event:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::logging::write(
+            $crate::logging::Level::$level,
+            $event,
+            &[$((stringify!($key), &$value as &dyn std::fmt::Display)),*],
+        )
+    };
+}
+
+pub(crate) use log;
+
+pub fn write(level: Level, event: &str, fields: &[(&str, &dyn Display)]) {
+    let line = format_line(&format_time(history::now_millis()), level, event, fields);
+
+    // Write the whole line at once, so lines from different tasks don't mix.
+    eprint!("{line}");
+}
+
+fn format_line(time: &str, level: Level, event: &str, fields: &[(&str, &dyn Display)]) -> String {
+    let mut line = format!("time={time} level={} event={event}", level.name());
+
+    for (key, value) in fields {
+        let value = value.to_string();
+        let plain = !value.is_empty()
+            && !value
+                .contains(|c: char| c.is_whitespace() || c.is_control() || c == '"' || c == '=');
+
+        if plain {
+            let _ = write!(line, " {key}={value}");
+        } else {
+            let _ = write!(line, " {key}={value:?}");
+        }
+    }
+
+    line.push('\n');
+    line
+}
+
+/// Format 'millis', in milliseconds since the Unix epoch, as an RFC 3339 UTC
+/// time.
+fn format_time(millis: u64) -> String {
+    let seconds = millis / 1000;
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let second_of_day = seconds % 86_400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60,
+        millis % 1000
+    )
+}
+
+/// Return the year, month and day of the date 'days' days after 1970-01-01,
+/// using Howard Hinnant's algorithm for the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months are counted from March, so that the leap day comes last.
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month as u32, day as u32)
+}
+
+#[test]
+fn test_format_line() {
+    assert_eq!(format_time(0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(format_time(1_722_935_702_417), "2024-08-06T09:15:02.417Z");
+    assert_eq!(format_time(951_782_400_000), "2000-02-29T00:00:00.000Z");
+
+    let line = format_line(
+        "T",
+        Level::Warn,
+        "connection_error",
+        &[
+            ("peer", &"127.0.0.1:53114"),
+            ("error", &"Too many \"failed\" logins"),
+            ("nickname", &""),
+        ],
+    );
+    assert_eq!(
+        line,
+        "time=T level=warn event=connection_error peer=127.0.0.1:53114 \
+         error=\"Too many \\\"failed\\\" logins\" nickname=\"\"\n"
+    );
+}
@@ -1,38 +1,227 @@
-use async_chat::utils::ChatResult;
-use async_std::prelude::*;
+use async_chat::runtime::{self, TcpListener, TcpStream};
+use async_chat::tls::{self, TlsAcceptor};
+use async_chat::utils::{ChatResult, ChatStream};
+use futures::prelude::*;
+use futures_lite::FutureExt as _;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+mod accounts;
+mod admin;
+mod attachments;
+mod config;
 mod connection;
+mod connection_table;
+mod federation;
 mod group;
 mod group_table;
+#[cfg(test)]
+mod harness;
+mod history;
+mod logging;
+mod message_log;
+mod metrics;
+mod outbound;
+mod plugins;
+mod rate_limit;
+mod shutdown;
+mod state;
+mod user_table;
+mod websocket;
 
+use admin::serve_admin;
+use config::ServerConfig;
 use connection::serve;
+use logging::log;
+use shutdown::Shutdown;
+use state::ServerState;
+use websocket::serve_websocket;
 
 fn main() -> ChatResult<()> {
-    let address = std::env::args().nth(1).expect("Usage: server ADDRESS");
-    let chat_group_table = Arc::new(group_table::GroupTable::new());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["hash-password", nickname] => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            println!("{}", accounts::password_line(nickname, password)?);
+            return Ok(());
+        }
+        ["new-token", nickname] => {
+            let (token, line) = accounts::new_token(nickname)?;
+            eprintln!("token: {token}");
+            println!("{line}");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let config = match ServerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{message}\n{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
+    let state = Arc::new(ServerState::new(&config)?);
+    let acceptor = match &config.tls {
+        Some(tls_config) => Some(tls::acceptor(&tls_config.cert, &tls_config.key)?),
+        None => None,
+    };
+
+    let shutdown = state.shutdown.clone();
+    ctrlc::set_handler(move || {
+        // A second signal means the user is tired of waiting.
+        if shutdown.is_triggered() {
+            std::process::exit(1);
+        }
+        shutdown.trigger();
+    })
+    .map_err(|error| format!("Could not install signal handler: {error}"))?;
+
+    runtime::block_on(async {
+        state.groups.restore().await?;
+        if let Some(admin_address) = &config.admin_address {
+            let listener = TcpListener::bind(admin_address).await?;
+            log!(
+                Info,
+                "listening",
+                service = "admin",
+                address = listener.local_addr()?
+            );
+            runtime::spawn(serve_admin(listener, state.clone()));
+        }
+
+        if let Some(relay_address) = &config.relay_address {
+            let listener = TcpListener::bind(relay_address).await?;
+            log!(
+                Info,
+                "listening",
+                service = "relay",
+                address = listener.local_addr()?
+            );
+            runtime::spawn(federation::serve_relay(listener, state.clone()));
+        }
+        for peer in &config.peers {
+            runtime::spawn(federation::keep_linked(peer.clone(), state.clone()));
+        }
 
-    async_std::task::block_on(async {
-        use async_std::{net, task};
+        if let Some(ws_address) = &config.ws_address {
+            let listener = TcpListener::bind(ws_address).await?;
+            let state = state.clone();
+            log!(
+                Info,
+                "listening",
+                service = "websocket",
+                address = listener.local_addr()?
+            );
 
-        let listener = net::TcpListener::bind(address).await?;
-        let mut new_connections = listener.incoming();
+            runtime::spawn(accept_connections(
+                listener,
+                acceptor.clone(),
+                state.shutdown.clone(),
+                move |stream, peer| serve_websocket(stream, peer, state.clone()),
+            ));
+        }
 
-        while let Some(socket_result) = new_connections.next().await {
-            let socket = socket_result?;
-            let groups = chat_group_table.clone();
+        let listener = TcpListener::bind(&config.address).await?;
+        let serve_state = state.clone();
+        log!(
+            Info,
+            "listening",
+            service = "chat",
+            address = listener.local_addr()?
+        );
+        accept_connections(
+            listener,
+            acceptor,
+            state.shutdown.clone(),
+            move |stream, peer| serve(stream, peer, serve_state.clone()),
+        )
+        .await;
 
-            task::spawn(async {
-                log_error(serve(socket, groups).await);
-            });
+        log!(
+            Info,
+            "shutdown_started",
+            connections = state.connections.len()
+        );
+        if runtime::timeout(SHUTDOWN_GRACE, state.shutdown.idle())
+            .await
+            .is_err()
+        {
+            log!(
+                Warn,
+                "shutdown_timed_out",
+                connections = state.connections.len()
+            );
         }
+        state.groups.sync_logs().await;
 
         Ok(())
     })
 }
 
-fn log_error(result: ChatResult<()>) {
-    if let Err(error) = result {
-        eprintln!("Error: {error}");
+/// How long to wait for connections to close once a shutdown has begun.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Accept connections on 'listener', wrap each in TLS if 'acceptor' is given,
+/// and hand it to 'serve' on a task of its own. Stop when 'shutdown' is
+/// triggered.
+async fn accept_connections<F, Fut>(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    shutdown: Arc<Shutdown>,
+    serve: F,
+) where
+    F: Fn(Box<dyn ChatStream>, SocketAddr) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ChatResult<()>> + Send + 'static,
+{
+    loop {
+        let next = async { Some(listener.accept().await) };
+        let stop = async {
+            shutdown.triggered().await;
+            None
+        };
+        let socket_result = match next.race(stop).await {
+            Some(socket_result) => socket_result,
+            None => break,
+        };
+
+        let (socket, peer) = match socket_result {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                log!(Warn, "accept_failed", error = error);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let serve = serve.clone();
+        let guard = shutdown.connection();
+
+        runtime::spawn(async move {
+            let _guard = guard;
+            log!(Info, "connection_opened", peer = peer);
+
+            let result = match handshake(socket, acceptor).await {
+                Ok(stream) => serve(stream, peer).await,
+                Err(error) => Err(error),
+            };
+
+            match result {
+                Ok(()) => log!(Info, "connection_closed", peer = peer),
+                Err(error) => log!(Warn, "connection_error", peer = peer, error = error),
+            }
+        });
+    }
+}
+
+async fn handshake(
+    socket: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+) -> ChatResult<Box<dyn ChatStream>> {
+    match acceptor {
+        Some(acceptor) => Ok(Box::new(acceptor.accept(socket).await?)),
+        None => Ok(Box::new(socket)),
     }
 }
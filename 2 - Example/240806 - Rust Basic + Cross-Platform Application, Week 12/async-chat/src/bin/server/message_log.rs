@@ -0,0 +1,540 @@
+use async_chat::PostedMessage;
+use futures::channel::oneshot;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, SystemTime};
+
+use crate::logging::log;
+
+/// Where and how to keep the on-disk message logs.
+///
+/// Each group gets its own directory under 'directory', holding numbered
+/// segment files of JSON lines, one 'PostedMessage' per line. A message that
+/// is edited, deleted or reacted to is appended again in full, and the last
+/// copy is the one that counts. Once the newest segment grows past
+/// 'max_segment_bytes', a new one is started, and the oldest segments are
+/// deleted according to the retention settings.
+///
+/// Alongside the segments, 'access.json' holds the group's owner,
+/// moderators, invitations and bans, which are never rotated away.
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    pub directory: PathBuf,
+    pub max_segment_bytes: u64,
+    /// How many segments to keep per group, including the one being written.
+    pub max_segments: usize,
+    /// Segments last written longer ago than this are deleted.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            directory: PathBuf::new(),
+            max_segment_bytes: 1024 * 1024,
+            max_segments: 10,
+            max_age: None,
+        }
+    }
+}
+
+/// The name of the file in a group's directory holding its access settings.
+const ACCESS_FILE: &str = "access.json";
+
+/// Reads and writes every group's log on a thread of its own, in the order
+/// asked, so that nothing on the async threads, or holding a lock, waits on
+/// the disk.
+pub struct LogStore {
+    config: LogConfig,
+    requests: mpsc::Sender<Request>,
+    next_id: AtomicU64,
+}
+
+/// What the 'LogStore' thread is asked to do. Each open log has an id of its
+/// own, so a group that is dropped and loaded again gets a fresh one.
+enum Request {
+    Open {
+        id: u64,
+        group_name: Arc<String>,
+        reply: oneshot::Sender<io::Result<Contents>>,
+    },
+    Append {
+        id: u64,
+        message: PostedMessage,
+    },
+    SaveAccess {
+        id: u64,
+        access: Vec<u8>,
+    },
+    Close {
+        id: u64,
+    },
+    Sync {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+/// A log's messages and saved access settings, as read when it is opened.
+type Contents = (Vec<PostedMessage>, Option<Vec<u8>>);
+
+/// A group's log, as opened by 'LogStore::open'.
+pub struct OpenedLog {
+    pub log: GroupLog,
+    /// Every message the log still holds, oldest first.
+    pub messages: Vec<PostedMessage>,
+    /// The JSON last saved with 'GroupLog::save_access', if any.
+    pub access: Option<Vec<u8>>,
+}
+
+/// One group's open log. Writes are queued for the 'LogStore' thread, and
+/// the log is closed when this is dropped.
+pub struct GroupLog {
+    id: u64,
+    requests: mpsc::Sender<Request>,
+}
+
+impl LogStore {
+    pub fn start(config: LogConfig) -> LogStore {
+        let (requests, received) = mpsc::channel();
+        let thread_config = config.clone();
+        std::thread::spawn(move || serve_requests(thread_config, received));
+
+        LogStore {
+            config,
+            requests,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Return the names of all groups that have a log.
+    pub fn logged_groups(&self) -> io::Result<Vec<String>> {
+        logged_groups(&self.config)
+    }
+
+    /// Open the log for 'group_name', creating it if necessary, once
+    /// everything asked of the store so far has been done.
+    pub async fn open(&self, group_name: Arc<String>) -> io::Result<OpenedLog> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, replied) = oneshot::channel();
+        let _ = self.requests.send(Request::Open {
+            id,
+            group_name,
+            reply,
+        });
+
+        let (messages, access) = replied.await.map_err(|_| stopped())??;
+        let log = GroupLog {
+            id,
+            requests: self.requests.clone(),
+        };
+
+        Ok(OpenedLog {
+            log,
+            messages,
+            access,
+        })
+    }
+
+    /// Wait until everything written so far has reached the disk.
+    pub async fn sync(&self) {
+        let (reply, replied) = oneshot::channel();
+        let _ = self.requests.send(Request::Sync { reply });
+        let _ = replied.await;
+    }
+}
+
+impl GroupLog {
+    pub fn append(&self, message: &PostedMessage) {
+        let message = message.clone();
+        let _ = self.requests.send(Request::Append {
+            id: self.id,
+            message,
+        });
+    }
+
+    /// Save the group's access settings, replacing the previous ones.
+    pub fn save_access<A: Serialize>(&self, access: &A) {
+        if let Ok(access) = serde_json::to_vec(access) {
+            let _ = self.requests.send(Request::SaveAccess {
+                id: self.id,
+                access,
+            });
+        }
+    }
+}
+
+impl Drop for GroupLog {
+    fn drop(&mut self) {
+        let _ = self.requests.send(Request::Close { id: self.id });
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("The log thread has stopped")
+}
+
+/// Carry out 'requests' until every 'LogStore' and 'GroupLog' is gone.
+fn serve_requests(config: LogConfig, requests: mpsc::Receiver<Request>) {
+    let mut logs: HashMap<u64, (Arc<String>, MessageLog)> = HashMap::new();
+
+    for request in requests {
+        match request {
+            Request::Open {
+                id,
+                group_name,
+                reply,
+            } => {
+                let opened = MessageLog::open(&config, &group_name).and_then(|(log, messages)| {
+                    let access = log.load_access()?;
+                    Ok((log, messages, access))
+                });
+                let opened = opened.map(|(log, messages, access)| {
+                    logs.insert(id, (group_name, log));
+                    (messages, access)
+                });
+
+                // Whoever asked has given up waiting.
+                if reply.send(opened).is_err() {
+                    logs.remove(&id);
+                }
+            }
+            Request::Append { id, message } => {
+                if let Some((group_name, log)) = logs.get_mut(&id) {
+                    if let Err(error) = log.append(&message) {
+                        log!(
+                            Error,
+                            "group_log_write_failed",
+                            group = group_name,
+                            error = error
+                        );
+                    }
+                }
+            }
+            Request::SaveAccess { id, access } => {
+                if let Some((group_name, log)) = logs.get(&id) {
+                    if let Err(error) = log.save_access(&access) {
+                        log!(
+                            Error,
+                            "group_access_write_failed",
+                            group = group_name,
+                            error = error
+                        );
+                    }
+                }
+            }
+            Request::Close { id } => {
+                logs.remove(&id);
+            }
+            Request::Sync { reply } => {
+                for (group_name, log) in logs.values() {
+                    if let Err(error) = log.sync() {
+                        log!(
+                            Error,
+                            "group_log_sync_failed",
+                            group = group_name,
+                            error = error
+                        );
+                    }
+                }
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+/// The append-only log of one group's messages.
+struct MessageLog {
+    config: LogConfig,
+    directory: PathBuf,
+    segment: File,
+    segment_index: u64,
+    segment_bytes: u64,
+}
+
+impl MessageLog {
+    /// Open the log for 'group_name', creating it if necessary.
+    /// Return the log along with every message it still holds, oldest first.
+    fn open(config: &LogConfig, group_name: &str) -> io::Result<(MessageLog, Vec<PostedMessage>)> {
+        let directory = config.directory.join(encode_group_name(group_name));
+        fs::create_dir_all(&directory)?;
+
+        remove_expired_segments(config, &directory)?;
+
+        let mut messages = Vec::new();
+        let segments = list_segments(&directory)?;
+        for (_index, path) in &segments {
+            read_segment(path, &mut messages)?;
+        }
+        let messages = latest_copies(messages);
+
+        let segment_index = segments.last().map_or(1, |(index, _path)| *index);
+        let segment = open_segment(&directory, segment_index)?;
+        let segment_bytes = segment.metadata()?.len();
+
+        let log = MessageLog {
+            config: config.clone(),
+            directory,
+            segment,
+            segment_index,
+            segment_bytes,
+        };
+
+        Ok((log, messages))
+    }
+
+    fn append(&mut self, message: &PostedMessage) -> io::Result<()> {
+        if self.segment_bytes >= self.config.max_segment_bytes {
+            self.rotate()?;
+        }
+
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+
+        self.segment.write_all(line.as_bytes())?;
+        self.segment_bytes += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Return the access settings last saved with 'save_access', or 'None'
+    /// if there are none.
+    fn load_access(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.directory.join(ACCESS_FILE)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Save the group's access settings, replacing the previous ones. The
+    /// new file is written in full before it takes the old one's place, so
+    /// a crash leaves one or the other.
+    fn save_access(&self, access: &[u8]) -> io::Result<()> {
+        let path = self.directory.join(ACCESS_FILE);
+        let partial = path.with_extension("json.partial");
+
+        let mut file = File::create(&partial)?;
+        file.write_all(access)?;
+        file.sync_all()?;
+
+        fs::rename(&partial, &path)
+    }
+
+    /// Make sure everything appended so far has reached the disk.
+    fn sync(&self) -> io::Result<()> {
+        self.segment.sync_all()
+    }
+
+    /// Start a new segment, and apply the retention policy to the old ones.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.segment_index += 1;
+        self.segment = open_segment(&self.directory, self.segment_index)?;
+        self.segment_bytes = 0;
+
+        remove_expired_segments(&self.config, &self.directory)
+    }
+}
+
+/// Return the names of all groups that have a log under 'config.directory'.
+fn logged_groups(config: &LogConfig) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    if !config.directory.exists() {
+        return Ok(names);
+    }
+
+    for entry in fs::read_dir(&config.directory)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        if let Some(name) = entry.file_name().to_str().and_then(decode_group_name) {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+/// Delete the oldest segments in 'directory' beyond 'max_segments', and any
+/// that are older than 'max_age'. The newest segment is always kept.
+fn remove_expired_segments(config: &LogConfig, directory: &Path) -> io::Result<()> {
+    let mut segments = list_segments(directory)?;
+    segments.pop();
+
+    let excess = (segments.len() + 1).saturating_sub(config.max_segments.max(1));
+    let now = SystemTime::now();
+
+    for (position, (_index, path)) in segments.iter().enumerate() {
+        let expired = match config.max_age {
+            Some(max_age) => {
+                let modified = fs::metadata(path)?.modified()?;
+                now.duration_since(modified).unwrap_or_default() > max_age
+            }
+            None => false,
+        };
+
+        if position < excess || expired {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the segment files in 'directory', sorted by index.
+fn list_segments(directory: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("jsonl") {
+            continue;
+        }
+
+        let index = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        if let Some(index) = index {
+            segments.push((index, path));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+fn open_segment(directory: &Path, index: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(directory.join(format!("{index:08}.jsonl")))
+}
+
+fn read_segment(path: &Path, messages: &mut Vec<PostedMessage>) -> io::Result<()> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        // A line cut short by a crash mid-write is skipped rather than
+        // treated as fatal.
+        if let Ok(message) = serde_json::from_str(&line?) {
+            messages.push(message);
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep only the last copy of each numbered message in 'messages', sorted by
+/// number. Messages saved before numbering began are all kept, first.
+fn latest_copies(messages: Vec<PostedMessage>) -> Vec<PostedMessage> {
+    let mut latest: Vec<PostedMessage> = Vec::with_capacity(messages.len());
+    let mut positions = HashMap::new();
+
+    for message in messages {
+        match positions.get(&message.seq) {
+            Some(&position) if message.seq != 0 => latest[position] = message,
+            _ => {
+                positions.insert(message.seq, latest.len());
+                latest.push(message);
+            }
+        }
+    }
+
+    // A copy can outlive its original when old segments are removed.
+    latest.sort_by_key(|message| message.seq);
+    latest
+}
+
+/// Turn a group name into something safe to use as a directory name:
+/// ASCII letters, digits, '-' and '_' are kept, and every other byte is
+/// written as '%' followed by two hex digits.
+fn encode_group_name(name: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    encoded
+}
+
+fn decode_group_name(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[test]
+fn test_message_log_rotation_and_recovery() {
+    use async_chat::Reactions;
+    use std::sync::Arc;
+
+    let directory = crate::harness::scratch_dir("log");
+
+    let config = LogConfig {
+        directory: directory.clone(),
+        max_segment_bytes: 1,
+        max_segments: 3,
+        max_age: None,
+    };
+
+    let (mut log, restored) = MessageLog::open(&config, "Dogs & Cats").unwrap();
+    assert!(restored.is_empty());
+
+    let message = |timestamp| PostedMessage {
+        sender: Arc::new("jimb".to_string()),
+        message: Arc::new(format!("message {timestamp}")),
+        timestamp,
+        seq: timestamp,
+        attachment: None,
+        edited: false,
+        deleted: false,
+        reactions: Reactions::new(),
+    };
+    for timestamp in 1..=3 {
+        log.append(&message(timestamp)).unwrap();
+    }
+
+    // A changed message is appended again, replacing the original.
+    let mut edited = message(3);
+    edited.message = Arc::new("message 3, edited".to_string());
+    edited.edited = true;
+    log.append(&edited).unwrap();
+    drop(log);
+
+    // Every append past the first started a new segment, and only the
+    // newest three were kept.
+    let (_log, restored) = MessageLog::open(&config, "Dogs & Cats").unwrap();
+    let timestamps: Vec<u64> = restored.iter().map(|m| m.timestamp).collect();
+    assert_eq!(timestamps, vec![2, 3]);
+    assert_eq!(restored[1], edited);
+    assert_eq!(
+        logged_groups(&config).unwrap(),
+        vec!["Dogs & Cats".to_string()]
+    );
+
+    fs::remove_dir_all(&directory).unwrap();
+}
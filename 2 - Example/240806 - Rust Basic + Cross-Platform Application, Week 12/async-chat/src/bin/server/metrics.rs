@@ -0,0 +1,124 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters covering the whole server, shared by every connection.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connections accepted, whether or not they went on to log in.
+    pub connections_opened: AtomicU64,
+    pub logins: AtomicU64,
+    /// Logins refused for bad credentials.
+    pub login_failures: AtomicU64,
+    /// Messages accepted for posting to a group.
+    pub messages_posted: AtomicU64,
+    pub direct_messages: AtomicU64,
+    /// Messages from other servers delivered to groups here.
+    pub messages_relayed: AtomicU64,
+    /// Requests rejected by the per-connection rate limits.
+    pub requests_rate_limited: AtomicU64,
+    /// Packets written to clients.
+    pub packets_sent: AtomicU64,
+    /// Packets thrown away because a client's outbound queue was full.
+    pub packets_dropped: AtomicU64,
+    /// Group messages a member missed because it fell behind the group.
+    pub messages_lagged: AtomicU64,
+    /// Connections closed for falling too far behind.
+    pub slow_clients_disconnected: AtomicU64,
+}
+
+/// Values that are read off the server's tables when metrics are reported,
+/// rather than counted as things happen.
+pub struct Gauges {
+    pub connections: usize,
+    pub users: usize,
+    pub groups: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Return the metrics in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let counters = [
+            (
+                "connections_opened",
+                "Connections accepted.",
+                &self.connections_opened,
+            ),
+            ("logins", "Successful logins.", &self.logins),
+            (
+                "login_failures",
+                "Logins refused for bad credentials.",
+                &self.login_failures,
+            ),
+            (
+                "messages_posted",
+                "Messages posted to groups.",
+                &self.messages_posted,
+            ),
+            (
+                "direct_messages",
+                "Direct messages delivered.",
+                &self.direct_messages,
+            ),
+            (
+                "messages_relayed",
+                "Messages from other servers delivered to groups.",
+                &self.messages_relayed,
+            ),
+            (
+                "requests_rate_limited",
+                "Requests rejected by rate limits.",
+                &self.requests_rate_limited,
+            ),
+            (
+                "packets_sent",
+                "Packets written to clients.",
+                &self.packets_sent,
+            ),
+            (
+                "packets_dropped",
+                "Packets dropped from full outbound queues.",
+                &self.packets_dropped,
+            ),
+            (
+                "messages_lagged",
+                "Group messages skipped by members that fell behind.",
+                &self.messages_lagged,
+            ),
+            (
+                "slow_clients_disconnected",
+                "Connections closed for falling behind.",
+                &self.slow_clients_disconnected,
+            ),
+        ];
+        let gauges = [
+            ("connections", "Connections open.", gauges.connections),
+            ("users", "Users logged in.", gauges.users),
+            ("groups", "Groups in existence.", gauges.groups),
+        ];
+
+        let mut text = String::new();
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::Relaxed);
+            let _ = write!(
+                text,
+                "# HELP chat_{name}_total {help}\n\
+                 # TYPE chat_{name}_total counter\n\
+                 chat_{name}_total {value}\n"
+            );
+        }
+        for (name, help, value) in gauges {
+            let _ = write!(
+                text,
+                "# HELP chat_{name} {help}\n\
+                 # TYPE chat_{name} gauge\n\
+                 chat_{name} {value}\n"
+            );
+        }
+
+        text
+    }
+}
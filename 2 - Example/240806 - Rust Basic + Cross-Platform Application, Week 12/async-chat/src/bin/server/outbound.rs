@@ -0,0 +1,437 @@
+//! Delivering packets to one client.
+//!
+//! Packets for a client are put on a bounded queue and written out by a task
+//! of its own, so a client that reads slowly only holds up itself, never the
+//! group subscribers and connections sending to it. What happens when the
+//! queue fills up is set by 'SlowClientPolicy'.
+//!
+//! File transfers have a short queue of their own, which is never dropped
+//! from: whoever sends a file waits for room instead, since a lost chunk
+//! would spoil the whole file. The writer task takes turns between the two
+//! queues, so neither holds up the other.
+
+use async_chat::codec::Codec;
+use async_chat::protocol::Agreement;
+use async_chat::runtime;
+use async_chat::utils::{ChatError, ChatResult};
+use async_chat::{FromClient, FromServer};
+use futures::sink::{self, Sink, SinkExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+use crate::metrics::Metrics;
+
+type PacketSink = Pin<Box<dyn Sink<FromServer, Error = ChatError> + Send>>;
+
+/// How many file transfer packets may wait to be written to one client.
+const TRANSFER_CAPACITY: usize = 4;
+
+/// What to do with a client whose outbound queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Discard the oldest queued packets to make room, and tell the client
+    /// how many it missed.
+    DropOldest,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SlowClientPolicy, String> {
+        match name {
+            "drop-oldest" => Ok(SlowClientPolicy::DropOldest),
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            _ => Err(format!("Unknown slow client policy '{name}'")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutboundConfig {
+    /// How many packets may wait to be written to one client.
+    pub capacity: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> OutboundConfig {
+        OutboundConfig {
+            capacity: 1000,
+            policy: SlowClientPolicy::DropOldest,
+        }
+    }
+}
+
+pub struct Outbound {
+    queue: Arc<Queue>,
+    /// What was agreed in the 'Hello' exchange, or the legacy terms if the
+    /// client skipped it.
+    agreement: RwLock<Agreement>,
+}
+
+/// The state shared between an 'Outbound' and its writer task.
+struct Queue {
+    state: Mutex<QueueState>,
+    config: OutboundConfig,
+    /// Signalled when a packet is queued, or the queue is closed.
+    ready: Notify,
+    /// Signalled when a transfer packet is taken, or the queue is closed.
+    room: Notify,
+    /// Signalled when the queue is closed.
+    closed: Notify,
+    /// Signalled when the writer task exits.
+    finished: Notify,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    packets: VecDeque<FromServer>,
+    /// Packets of file transfers, which are never dropped.
+    transfers: VecDeque<FromServer>,
+    /// Whether the writer task should take from 'transfers' next, if both
+    /// queues have packets waiting.
+    transfer_turn: bool,
+    /// Packets dropped since the client was last told about it.
+    unreported_drops: u64,
+    /// Once set, nothing more is queued. The writer task finishes what's
+    /// already in the queue and exits.
+    closed: bool,
+    /// Set by the writer task as it exits.
+    finished: bool,
+}
+
+impl Outbound {
+    /// Send packets to 'to_client', encoded with 'codec'.
+    pub fn new<W>(
+        to_client: W,
+        codec: Codec,
+        config: OutboundConfig,
+        metrics: Arc<Metrics>,
+    ) -> Outbound
+    where
+        W: futures::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        let encoded = sink::unfold(
+            to_client,
+            move |mut to_client, packet: FromServer| async move {
+                codec.send(&mut to_client, &packet).await?;
+                futures::io::AsyncWriteExt::flush(&mut to_client).await?;
+                Ok::<_, ChatError>(to_client)
+            },
+        );
+
+        Outbound::from_sink(encoded, config, metrics)
+    }
+
+    /// Send packets to a sink that takes care of encoding them itself.
+    pub fn from_sink<K>(to_client: K, config: OutboundConfig, metrics: Arc<Metrics>) -> Outbound
+    where
+        K: Sink<FromServer, Error = ChatError> + Send + 'static,
+    {
+        let queue = Arc::new(Queue::new(config, metrics));
+
+        runtime::spawn(write_packets(queue.clone(), Box::pin(to_client)));
+
+        Outbound {
+            queue,
+            agreement: RwLock::new(Agreement::legacy()),
+        }
+    }
+
+    pub fn set_agreement(&self, agreement: Agreement) {
+        *self.agreement.write().unwrap() = agreement;
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.agreement.read().unwrap().supports(capability)
+    }
+
+    /// Return the capability 'request' needs that this connection lacks.
+    pub fn missing_capability(&self, request: &FromClient) -> Option<&'static str> {
+        request
+            .capability()
+            .filter(|capability| !self.supports(capability))
+    }
+
+    /// Queue 'packet' for the client, unless it needs a capability the client
+    /// doesn't have, in which case it is quietly dropped. Return an error if
+    /// the connection is closed, or was just closed for being too slow.
+    pub fn send(&self, packet: FromServer) -> ChatResult<()> {
+        if let Some(capability) = packet.capability() {
+            if !self.supports(capability) {
+                return Ok(());
+            }
+        }
+
+        self.queue.push(packet)
+    }
+
+    /// Queue 'packet', part of a file transfer, as 'send' does, but wait for
+    /// room rather than ever dropping it.
+    pub async fn send_transfer(&self, packet: FromServer) -> ChatResult<()> {
+        if let Some(capability) = packet.capability() {
+            if !self.supports(capability) {
+                return Ok(());
+            }
+        }
+
+        self.queue.push_transfer(packet).await
+    }
+
+    /// Wait until the connection is closed, whether by a write failing or
+    /// by the slow client policy.
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.queue.closed.notified();
+            if self.queue.state.lock().unwrap().closed {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Stop accepting packets, and wait until everything already queued has
+    /// been written to the client.
+    pub async fn finish(&self) {
+        self.queue.close(false);
+
+        loop {
+            let notified = self.queue.finished.notified();
+            if self.queue.state.lock().unwrap().finished {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Outbound {
+    fn drop(&mut self) {
+        // Let the writer task finish sending whatever is still queued.
+        self.queue.close(false);
+    }
+}
+
+impl Queue {
+    fn new(config: OutboundConfig, metrics: Arc<Metrics>) -> Queue {
+        Queue {
+            state: Mutex::new(QueueState::default()),
+            config,
+            ready: Notify::new(),
+            room: Notify::new(),
+            closed: Notify::new(),
+            finished: Notify::new(),
+            metrics,
+        }
+    }
+
+    fn push(&self, packet: FromServer) -> ChatResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err("Connection closed".into());
+        }
+
+        if state.packets.len() >= self.config.capacity {
+            match self.config.policy {
+                SlowClientPolicy::DropOldest => {
+                    state.packets.pop_front();
+                    state.unreported_drops += 1;
+                    self.metrics.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                SlowClientPolicy::Disconnect => {
+                    drop(state);
+                    self.metrics
+                        .slow_clients_disconnected
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.close(true);
+                    return Err("Client fell too far behind".into());
+                }
+            }
+        }
+
+        state.packets.push_back(packet);
+        drop(state);
+
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Queue a file transfer packet, waiting while 'TRANSFER_CAPACITY' are
+    /// already queued.
+    async fn push_transfer(&self, packet: FromServer) -> ChatResult<()> {
+        loop {
+            let room = self.room.notified();
+
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.closed {
+                    return Err("Connection closed".into());
+                }
+                if state.transfers.len() < TRANSFER_CAPACITY {
+                    state.transfers.push_back(packet);
+                    drop(state);
+
+                    self.ready.notify_one();
+                    return Ok(());
+                }
+            }
+
+            room.await;
+        }
+    }
+
+    /// Stop accepting packets. If 'discard' is true, also throw away any that
+    /// haven't been written yet.
+    fn close(&self, discard: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        state.closed = true;
+        if discard {
+            state.packets.clear();
+            state.transfers.clear();
+        }
+        drop(state);
+
+        self.ready.notify_one();
+        self.room.notify_waiters();
+        self.closed.notify_waiters();
+    }
+
+    /// Return the next packet to write, or 'None' once the queue is closed
+    /// and empty. Wait if the queue is open but empty.
+    async fn next_packet(&self) -> Option<FromServer> {
+        loop {
+            let ready = self.ready.notified();
+
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if state.unreported_drops > 0 {
+                    let n = std::mem::take(&mut state.unreported_drops);
+                    return Some(FromServer::Error(format!(
+                        "Dropped {n} messages because the connection fell behind."
+                    )));
+                }
+
+                let transfer = !state.transfers.is_empty()
+                    && (state.transfer_turn || state.packets.is_empty());
+                if transfer {
+                    state.transfer_turn = false;
+                    let packet = state.transfers.pop_front();
+                    drop(state);
+
+                    self.room.notify_waiters();
+                    return packet;
+                }
+                if let Some(packet) = state.packets.pop_front() {
+                    state.transfer_turn = true;
+                    return Some(packet);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+
+            ready.await;
+        }
+    }
+}
+
+async fn write_packets(queue: Arc<Queue>, mut to_client: PacketSink) {
+    while let Some(packet) = queue.next_packet().await {
+        if to_client.send(packet).await.is_err() {
+            queue.close(true);
+            break;
+        }
+
+        queue.metrics.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let _ = to_client.close().await;
+
+    queue.state.lock().unwrap().finished = true;
+    queue.finished.notify_waiters();
+}
+
+#[test]
+fn test_slow_client_policies() {
+    let packet = |text: &str| FromServer::Error(text.to_string());
+    let config = |policy| OutboundConfig {
+        capacity: 2,
+        policy,
+    };
+
+    runtime::block_on(async {
+        let metrics = Arc::new(Metrics::new());
+        let queue = Queue::new(config(SlowClientPolicy::DropOldest), metrics.clone());
+        for text in ["one", "two", "three"] {
+            queue.push(packet(text)).unwrap();
+        }
+
+        let notice = "Dropped 1 messages because the connection fell behind.";
+        assert_eq!(queue.next_packet().await, Some(packet(notice)));
+        assert_eq!(queue.next_packet().await, Some(packet("two")));
+        assert_eq!(queue.next_packet().await, Some(packet("three")));
+        assert_eq!(metrics.packets_dropped.load(Ordering::Relaxed), 1);
+
+        let metrics = Arc::new(Metrics::new());
+        let queue = Queue::new(config(SlowClientPolicy::Disconnect), metrics.clone());
+        queue.push(packet("one")).unwrap();
+        queue.push(packet("two")).unwrap();
+        assert!(queue.push(packet("three")).is_err());
+        assert!(queue.push(packet("four")).is_err());
+
+        assert_eq!(queue.next_packet().await, None);
+        assert_eq!(metrics.slow_clients_disconnected.load(Ordering::Relaxed), 1);
+    });
+}
+
+#[test]
+fn test_transfers() {
+    use futures::FutureExt;
+
+    let packet = |text: &str| FromServer::Error(text.to_string());
+    let config = OutboundConfig {
+        capacity: 1,
+        policy: SlowClientPolicy::DropOldest,
+    };
+
+    runtime::block_on(async {
+        let queue = Queue::new(config, Arc::new(Metrics::new()));
+        for i in 0..TRANSFER_CAPACITY {
+            queue.push_transfer(packet(&i.to_string())).await.unwrap();
+        }
+
+        // A full transfer queue makes the sender wait, where the other queue
+        // would drop packets.
+        let mut waiting = Box::pin(queue.push_transfer(packet("last")));
+        assert!((&mut waiting).now_or_never().is_none());
+        queue.push(packet("a")).unwrap();
+        queue.push(packet("b")).unwrap();
+
+        // The writer takes turns between the queues, and taking a transfer
+        // packet makes room for another.
+        let notice = "Dropped 1 messages because the connection fell behind.";
+        assert_eq!(queue.next_packet().await, Some(packet(notice)));
+        assert_eq!(queue.next_packet().await, Some(packet("b")));
+        assert_eq!(queue.next_packet().await, Some(packet("0")));
+        assert!(waiting.now_or_never().unwrap().is_ok());
+        for i in 1..TRANSFER_CAPACITY {
+            assert_eq!(queue.next_packet().await, Some(packet(&i.to_string())));
+        }
+        assert_eq!(queue.next_packet().await, Some(packet("last")));
+
+        // Closing the queue stops senders waiting for room.
+        queue.close(true);
+        assert!(queue.push_transfer(packet("late")).await.is_err());
+    });
+}
@@ -0,0 +1,163 @@
+//! Server-side bots.
+//!
+//! A plugin sees every message posted to every group, and may answer by
+//! posting to the group itself. Plugins are enabled with '--plugin NAME';
+//! 'BUILT_IN' lists the names this server knows.
+
+use std::sync::Arc;
+
+use crate::group::Group;
+use crate::logging::log;
+
+/// The plugins '--plugin' can enable.
+pub const BUILT_IN: &[&str] = &["help", "echo"];
+
+const HELP_USAGE: &str = "!help: list these commands";
+
+pub trait Plugin: Send + Sync {
+    /// What '--plugin' calls this plugin. Replies are posted as 'NAME bot'.
+    fn name(&self) -> &'static str;
+
+    /// The commands this plugin answers, for '!help' to list.
+    fn usage(&self) -> &'static str;
+
+    /// React to 'sender' posting 'message' to 'group'. This is called on
+    /// the sender's connection task, after the message has gone out, so it
+    /// shouldn't block; a plugin with slow work to do should spawn a task.
+    fn on_post(&self, group: &Arc<Group>, sender: &Arc<String>, message: &Arc<String>);
+}
+
+/// The plugins this server runs, in the order they're called.
+pub struct Plugins {
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl Plugins {
+    pub fn new() -> Plugins {
+        Plugins {
+            plugins: Vec::new(),
+        }
+    }
+
+    /// Enable the built-in plugins named in 'names'.
+    pub fn from_names(names: &[String]) -> Result<Plugins, String> {
+        let mut plugins = Plugins::new();
+        for name in names {
+            match name.as_str() {
+                // Added last, so it can list everyone else.
+                "help" => {}
+                "echo" => plugins.register(Box::new(Echo)),
+                _ => return Err(format!("Unknown plugin '{name}'")),
+            }
+        }
+
+        if names.iter().any(|name| name == "help") {
+            let mut usage = vec![HELP_USAGE];
+            usage.extend(plugins.plugins.iter().map(|plugin| plugin.usage()));
+            plugins.register(Box::new(Help { usage }));
+        }
+
+        Ok(plugins)
+    }
+
+    pub fn register(&mut self, plugin: Box<dyn Plugin>) {
+        self.plugins.push(plugin);
+    }
+
+    pub fn on_post(&self, group: &Arc<Group>, sender: &Arc<String>, message: &Arc<String>) {
+        for plugin in &self.plugins {
+            plugin.on_post(group, sender, message);
+        }
+    }
+}
+
+/// Post 'text' to 'group' on behalf of the plugin named 'plugin'. Nicknames
+/// can't contain spaces, so no user can pass for the bot.
+pub fn reply(group: &Group, plugin: &str, text: String) {
+    let bot = Arc::new(format!("{plugin} bot"));
+    if let Err(error) = group.post(bot, Arc::new(text)) {
+        log!(Warn, "plugin_reply_failed", plugin = plugin, error = error);
+    }
+}
+
+/// Answers '!help' with the commands of every plugin.
+struct Help {
+    usage: Vec<&'static str>,
+}
+
+impl Plugin for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        HELP_USAGE
+    }
+
+    fn on_post(&self, group: &Arc<Group>, _sender: &Arc<String>, message: &Arc<String>) {
+        if message.trim() == "!help" {
+            reply(group, self.name(), self.usage.join("; "));
+        }
+    }
+}
+
+/// Repeats whatever follows '!echo', for testing clients.
+struct Echo;
+
+impl Plugin for Echo {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn usage(&self) -> &'static str {
+        "!echo TEXT: repeat TEXT"
+    }
+
+    fn on_post(&self, group: &Arc<Group>, sender: &Arc<String>, message: &Arc<String>) {
+        if let Some(text) = message.strip_prefix("!echo ") {
+            reply(group, self.name(), format!("{sender} said: {text}"));
+        }
+    }
+}
+
+#[test]
+fn test_plugins() {
+    use crate::federation::Federation;
+    use crate::harness::name;
+    use crate::history::HistoryConfig;
+    use crate::metrics::Metrics;
+    use crate::rate_limit::RateLimitConfig;
+
+    let group = Arc::new(Group::new(
+        name("Dogs"),
+        HistoryConfig::default(),
+        None,
+        RateLimitConfig::default().group,
+        Arc::new(Metrics::new()),
+        Arc::new(Federation::new(None, Arc::new(Metrics::new())).unwrap()),
+    ));
+
+    assert!(Plugins::from_names(&["bark".to_string()]).is_err());
+    let plugins = Plugins::from_names(&["help".to_string(), "echo".to_string()]).unwrap();
+
+    for message in ["!echo woof", "woof", "!help"] {
+        group.post(name("fido"), name(message)).unwrap();
+        plugins.on_post(&group, &name("fido"), &name(message));
+    }
+
+    let posted: Vec<_> = group
+        .history(None, 10)
+        .into_iter()
+        .map(|posted| format!("{}: {}", posted.sender, posted.message))
+        .collect();
+    assert_eq!(
+        posted,
+        [
+            "fido: !echo woof",
+            "echo bot: fido said: woof",
+            "fido: woof",
+            "fido: !help",
+            "help bot: !help: list these commands; !echo TEXT: repeat TEXT",
+        ]
+    );
+}
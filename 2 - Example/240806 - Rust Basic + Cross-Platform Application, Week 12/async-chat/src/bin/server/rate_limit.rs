@@ -0,0 +1,278 @@
+//! Flood protection.
+//!
+//! Each connection may send messages at a limited rate, with room for short
+//! bursts, and each group accepts messages at a limited rate however many
+//! members are posting. A connection that keeps breaking the limits is
+//! disconnected.
+
+use async_chat::FromClient;
+use std::time::Instant;
+
+/// A sustained rate, plus how far a burst may run ahead of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitConfig {
+    /// How fast one connection may send messages, and edit, delete or react
+    /// to them.
+    pub client: Rate,
+    /// How fast one group accepts posts, from all its members together.
+    pub group: Rate,
+    /// The longest message accepted, in bytes.
+    pub max_message_length: usize,
+    /// How many rejected requests a connection may make in a minute before
+    /// it is disconnected.
+    pub max_violations: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            client: Rate {
+                per_second: 5.0,
+                burst: 10,
+            },
+            group: Rate {
+                per_second: 50.0,
+                burst: 100,
+            },
+            max_message_length: 4096,
+            max_violations: 10,
+        }
+    }
+}
+
+/// A bucket holding up to 'rate.burst' tokens, refilled at 'rate.per_second'.
+/// Each action takes a token; when the bucket is empty, the action must wait.
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Return a full bucket.
+    pub fn new(rate: Rate) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one. Return 'false' if the bucket is empty.
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst as f64);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// One connection's limits.
+pub struct FloodGuard {
+    max_message_length: usize,
+    messages: TokenBucket,
+    /// Holds a token for each rejected request the connection may still make.
+    tolerance: TokenBucket,
+}
+
+impl FloodGuard {
+    pub fn new(config: &RateLimitConfig) -> FloodGuard {
+        let tolerance = Rate {
+            per_second: config.max_violations as f64 / 60.0,
+            burst: config.max_violations,
+        };
+
+        FloodGuard {
+            max_message_length: config.max_message_length,
+            messages: TokenBucket::new(config.client),
+            tolerance: TokenBucket::new(tolerance),
+        }
+    }
+
+    /// Check 'request' against this connection's limits, returning an
+    /// explanation if it must be rejected.
+    pub fn check(&mut self, request: &FromClient) -> Result<(), String> {
+        let message = match request {
+            FromClient::Post { message, .. }
+            | FromClient::DirectMessage { message, .. }
+            | FromClient::Offer { message, .. }
+            | FromClient::Edit { message, .. } => Some(message),
+            FromClient::Delete { .. } | FromClient::React { .. } => None,
+            _ => return Ok(()),
+        };
+
+        if let Some(message) = message.filter(|m| m.len() > self.max_message_length) {
+            return Err(format!(
+                "Message of {} bytes exceeds the limit of {}",
+                message.len(),
+                self.max_message_length
+            ));
+        }
+
+        if !self.messages.take() {
+            return Err("You are sending messages too quickly".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Count a rejected request against the connection. Return 'true' if
+    /// it has had too many, and should be disconnected.
+    pub fn record_violation(&mut self) -> bool {
+        !self.tolerance.take()
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    use std::time::Duration;
+
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(Rate {
+        per_second: 2.0,
+        burst: 3,
+    });
+    bucket.updated = start;
+
+    // A full bucket allows a burst, then nothing until it refills.
+    assert!((0..3).all(|_| bucket.take_at(start)));
+    assert!(!bucket.take_at(start));
+
+    let later = start + Duration::from_millis(500);
+    assert!(bucket.take_at(later));
+    assert!(!bucket.take_at(later));
+
+    // A long pause refills the bucket, but no further than the burst.
+    let much_later = later + Duration::from_secs(60);
+    assert!((0..3).all(|_| bucket.take_at(much_later)));
+    assert!(!bucket.take_at(much_later));
+}
+
+#[test]
+fn test_flood_guard() {
+    use crate::harness::name;
+
+    let config = RateLimitConfig {
+        client: Rate {
+            per_second: 0.001,
+            burst: 2,
+        },
+        max_message_length: 4,
+        max_violations: 2,
+        ..RateLimitConfig::default()
+    };
+    let mut guard = FloodGuard::new(&config);
+    let post = |message: &str| FromClient::Post {
+        group_name: name("Dogs"),
+        message: name(message),
+        id: None,
+    };
+
+    // Long messages are refused without using up the rate.
+    assert_eq!(
+        guard.check(&post("woof!")),
+        Err("Message of 5 bytes exceeds the limit of 4".to_string())
+    );
+    let edit = FromClient::Edit {
+        group_name: name("Dogs"),
+        seq: 1,
+        message: name("arf arf"),
+    };
+    assert!(guard.check(&edit).is_err());
+
+    // Messages beyond the burst are refused, but other requests aren't
+    // limited.
+    assert!(guard.check(&post("woof")).is_ok());
+    assert!(guard.check(&post("arf")).is_ok());
+    assert_eq!(
+        guard.check(&post("yip")),
+        Err("You are sending messages too quickly".to_string())
+    );
+    assert!(guard.check(&FromClient::ListGroups).is_ok());
+
+    // Only violations beyond 'max_violations' call for a disconnection.
+    assert!(!guard.record_violation());
+    assert!(!guard.record_violation());
+    assert!(guard.record_violation());
+}
+
+#[test]
+fn test_flooding() {
+    use crate::harness::{next_message, next_packet, TestServer};
+    use async_chat::{runtime, FromServer};
+
+    runtime::block_on(async {
+        let server = TestServer::start(&[
+            "--post-burst",
+            "100",
+            "--group-post-rate",
+            "0.001",
+            "--group-post-burst",
+            "2",
+            "--max-message-length",
+            "10",
+            "--max-violations",
+            "3",
+        ])
+        .await;
+        let mut fido = server.member("fido", "Dogs").await;
+        let mut rex = server.member("rex", "Dogs").await;
+
+        // Each group takes only so many posts, whoever sends them.
+        fido.post("Dogs", "woof").await.unwrap();
+        assert_eq!(next_message(&mut rex).await, "fido: woof");
+        rex.post("Dogs", "arf").await.unwrap();
+        assert_eq!(next_message(&mut rex).await, "rex: arf");
+        let id = fido.post("Dogs", "yip").await.unwrap();
+        let refusal = loop {
+            match next_packet(&mut fido).await.unwrap() {
+                FromServer::Rejected {
+                    id: rejected,
+                    reason,
+                } if rejected == id => break reason,
+                _ => {}
+            }
+        };
+        assert_eq!(
+            refusal,
+            "Group 'Dogs' is receiving too many messages; try again shortly"
+        );
+
+        // A client that keeps breaking the limits is disconnected.
+        let long = "a".repeat(11);
+        for _ in 0..4 {
+            rex.post("Dogs", &long).await.unwrap();
+        }
+        let mut packets = Vec::new();
+        while let Some(packet) = next_packet(&mut rex).await {
+            packets.push(packet);
+        }
+        let rejections = packets
+            .iter()
+            .filter(|packet| matches!(packet, FromServer::Rejected { .. }))
+            .count();
+        assert_eq!(rejections, 4);
+        assert_eq!(
+            packets.last(),
+            Some(&FromServer::Error(
+                "Disconnected for breaking the rate limits".to_string()
+            ))
+        );
+    });
+}
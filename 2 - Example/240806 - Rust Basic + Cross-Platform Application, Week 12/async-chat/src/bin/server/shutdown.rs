@@ -0,0 +1,75 @@
+//! Stopping the server cleanly.
+//!
+//! When 'Shutdown::trigger' is called, the listeners stop accepting
+//! connections, and every connection tells its client the server is going
+//! away, sends whatever it still has queued, and closes. 'Shutdown::idle'
+//! resolves once they have all finished.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Default)]
+pub struct Shutdown {
+    triggered: AtomicBool,
+    /// Signalled when 'triggered' is set.
+    notify: Notify,
+    connections: AtomicUsize,
+    /// Signalled when the last connection finishes.
+    idle: Notify,
+}
+
+/// Counts a connection as open until dropped.
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        if !self.triggered.swap(true, Ordering::SeqCst) {
+            self.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Wait until 'trigger' is called.
+    pub async fn triggered(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_triggered() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Count a new connection as open, until the returned guard is dropped.
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Wait until no connections are open.
+    pub async fn idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.connections.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.0.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::accounts::Accounts;
+use crate::attachments::AttachmentStore;
+use crate::config::ServerConfig;
+use crate::connection_table::ConnectionTable;
+use crate::federation::Federation;
+use crate::group_table::GroupTable;
+use crate::metrics::Metrics;
+use crate::outbound::OutboundConfig;
+use crate::plugins::Plugins;
+use crate::rate_limit::RateLimitConfig;
+use crate::shutdown::Shutdown;
+use crate::user_table::UserTable;
+use async_chat::utils::ChatResult;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Everything the server's connections share.
+pub struct ServerState {
+    pub groups: GroupTable,
+    pub users: UserTable,
+    pub connections: ConnectionTable,
+    pub outbound: OutboundConfig,
+    pub limits: RateLimitConfig,
+    pub idle_timeout: Option<Duration>,
+    /// 'None' if anyone may log in.
+    pub accounts: Option<Accounts>,
+    /// 'None' unless clients may share files.
+    pub attachments: Option<AttachmentStore>,
+    pub metrics: Arc<Metrics>,
+    pub federation: Arc<Federation>,
+    pub plugins: Plugins,
+    pub shutdown: Arc<Shutdown>,
+}
+
+impl ServerState {
+    pub fn new(config: &ServerConfig) -> ChatResult<ServerState> {
+        let accounts = match &config.users {
+            Some(path) => Some(Accounts::load(path)?),
+            None => None,
+        };
+
+        let attachments = match &config.attachments {
+            Some(attachment_config) => Some(AttachmentStore::open(attachment_config)?),
+            None => None,
+        };
+
+        let metrics = Arc::new(Metrics::new());
+        let plugins = Plugins::from_names(&config.plugins)?;
+        let federation = Arc::new(Federation::new(config.node.clone(), metrics.clone())?);
+
+        Ok(ServerState {
+            groups: GroupTable::new(
+                config.history,
+                config.log.clone(),
+                config.limits.group,
+                metrics.clone(),
+                federation.clone(),
+            ),
+            users: UserTable::new(),
+            connections: ConnectionTable::new(),
+            outbound: config.outbound,
+            limits: config.limits,
+            idle_timeout: config.idle_timeout,
+            accounts,
+            attachments,
+            metrics,
+            federation,
+            plugins,
+            shutdown: Arc::new(Shutdown::new()),
+        })
+    }
+}
@@ -0,0 +1,38 @@
+use crate::outbound::Outbound;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The nicknames currently logged in, and the connection each one belongs to.
+pub struct UserTable(Mutex<HashMap<Arc<String>, Arc<Outbound>>>);
+
+impl UserTable {
+    pub fn new() -> UserTable {
+        UserTable(Mutex::new(HashMap::new()))
+    }
+
+    /// Claim 'nickname' for 'outbound'.
+    /// Return 'false' if some other connection is already using it.
+    pub fn login(&self, nickname: Arc<String>, outbound: Arc<Outbound>) -> bool {
+        let mut users = self.0.lock().unwrap();
+
+        if users.contains_key(&nickname) {
+            return false;
+        }
+
+        users.insert(nickname, outbound);
+        true
+    }
+
+    /// Return the connection of the user logged in as 'nickname', if any.
+    pub fn get(&self, nickname: &String) -> Option<Arc<Outbound>> {
+        self.0.lock().unwrap().get(nickname).cloned()
+    }
+
+    pub fn logout(&self, nickname: &String) {
+        self.0.lock().unwrap().remove(nickname);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
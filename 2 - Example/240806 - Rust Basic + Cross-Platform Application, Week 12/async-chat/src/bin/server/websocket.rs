@@ -0,0 +1,148 @@
+//! Serving chat clients that connect over WebSocket, such as browsers.
+//!
+//! Each text frame carries one 'FromClient' or 'FromServer' packet as JSON,
+//! exactly as a line does on a plain connection, so WebSocket users and
+//! terminal users share the same groups.
+
+use async_chat::utils::{ChatError, ChatResult, ChatStream};
+use async_chat::{FromClient, FromServer};
+use async_tungstenite::tungstenite::Message;
+use futures::future;
+use futures::prelude::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::connection;
+use crate::connection_table::Transport;
+use crate::outbound::Outbound;
+use crate::state::ServerState;
+
+pub async fn serve_websocket<S>(
+    socket: S,
+    peer: SocketAddr,
+    state: Arc<ServerState>,
+) -> ChatResult<()>
+where
+    S: ChatStream + 'static,
+{
+    let websocket = async_tungstenite::accept_async(socket).await?;
+    let (to_client, from_client) = websocket.split();
+
+    let to_client = to_client
+        .sink_map_err(ChatError::from)
+        .with(|packet: FromServer| {
+            future::ready(
+                serde_json::to_string(&packet)
+                    .map(Message::text)
+                    .map_err(ChatError::from),
+            )
+        });
+    let outbound = Arc::new(Outbound::from_sink(
+        to_client,
+        state.outbound,
+        state.metrics.clone(),
+    ));
+
+    // Pings are answered by 'async_tungstenite' itself, and a 'Close' frame
+    // ends the stream, so only data frames are left to parse.
+    let from_client = futures_lite::StreamExt::filter_map(from_client, |frame| match frame {
+        Ok(Message::Text(text)) => Some(parse_request(&text)),
+        Ok(Message::Binary(_)) => Some(Err("Expected a text frame".into())),
+        Ok(_) => None,
+        Err(error) => Some(Err(error.into())),
+    });
+
+    let from_client = Box::pin(from_client);
+    connection::serve_requests(from_client, outbound, peer, Transport::WebSocket, state).await
+}
+
+fn parse_request(text: &str) -> ChatResult<FromClient> {
+    Ok(serde_json::from_str(text)?)
+}
+
+#[test]
+fn test_websocket_and_tcp_clients_share_groups() {
+    use crate::config::ServerConfig;
+    use async_chat::runtime::{self, TcpListener, TcpStream};
+    use async_chat::utils;
+    use futures::io::BufReader;
+
+    let config = ServerConfig::from_args(vec!["127.0.0.1:0".to_string()]).unwrap();
+    let state = Arc::new(ServerState::new(&config).unwrap());
+
+    runtime::block_on(async {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_address = tcp_listener.local_addr().unwrap();
+        let ws_address = ws_listener.local_addr().unwrap();
+
+        let tcp_state = state.clone();
+        runtime::spawn(async move {
+            let (socket, peer) = tcp_listener.accept().await.unwrap();
+            let _ = connection::serve(socket, peer, tcp_state).await;
+        });
+        runtime::spawn(async move {
+            let (socket, peer) = ws_listener.accept().await.unwrap();
+            let _ = serve_websocket(socket, peer, state).await;
+        });
+
+        let socket = TcpStream::connect(&ws_address.to_string()).await.unwrap();
+        let url = format!("ws://{ws_address}/");
+        let (mut browser, _) = async_tungstenite::client_async(url, socket).await.unwrap();
+
+        let terminal = TcpStream::connect(&tcp_address.to_string()).await.unwrap();
+        let (reader, mut terminal) = terminal.split();
+        let mut from_terminal = utils::receive_as_json(BufReader::new(reader));
+
+        let send_text = |request: &str| Message::text(request.to_string());
+        browser
+            .send(send_text(r#"{"Login":{"nickname":"browser"}}"#))
+            .await
+            .unwrap();
+        browser
+            .send(send_text(r#"{"Join":{"group_name":"Dogs"}}"#))
+            .await
+            .unwrap();
+
+        async fn next_packet<S>(websocket: &mut S) -> FromServer
+        where
+            S: Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>> + Unpin,
+        {
+            let frame = websocket.next().await.unwrap().unwrap();
+            serde_json::from_str(frame.to_text().unwrap()).unwrap()
+        }
+
+        let reply = next_packet(&mut browser).await;
+        assert!(matches!(reply, FromServer::LoggedIn { .. }));
+        let reply = next_packet(&mut browser).await;
+        assert!(matches!(reply, FromServer::HistoryEnd { .. }));
+
+        let requests = [
+            FromClient::Login {
+                nickname: Arc::new("terminal".to_string()),
+                credential: None,
+            },
+            FromClient::Post {
+                group_name: Arc::new("Dogs".to_string()),
+                message: Arc::new("Samoyeds rock!".to_string()),
+                id: None,
+            },
+        ];
+        for request in &requests {
+            utils::send_as_json(&mut terminal, request).await.unwrap();
+        }
+
+        let reply: FromServer = from_terminal.next().await.unwrap().unwrap();
+        assert!(matches!(reply, FromServer::LoggedIn { .. }));
+
+        match next_packet(&mut browser).await {
+            FromServer::Message {
+                sender, message, ..
+            } => {
+                assert_eq!(sender.as_str(), "terminal");
+                assert_eq!(message.as_str(), "Samoyeds rock!");
+            }
+            other => panic!("unexpected packet: {other:?}"),
+        }
+    });
+}
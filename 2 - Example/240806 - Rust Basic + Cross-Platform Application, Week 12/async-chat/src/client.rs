@@ -0,0 +1,422 @@
+//! A client for programs that talk to a chat server.
+//!
+//! ```no_run
+//! # use async_chat::client::ChatClient;
+//! # use async_chat::utils::ChatResult;
+//! # use futures::prelude::*;
+//! # async fn example() -> ChatResult<()> {
+//! let mut client = ChatClient::connect("localhost:8088").await?;
+//! client.login("echo-bot", None).await?;
+//! client.join("Bots").await?;
+//!
+//! while let Some(event) = client.next().await {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A 'ChatClient' is both the way to send requests and a 'Stream' of
+//! whatever the server sends back. A program that needs to do both at once
+//! can 'split' it into a 'ChatSender' and a stream of its own.
+//!
+//! Each post is numbered, and if the server supports 'protocol::ACKS', it
+//! answers with a 'FromServer::Ack' or 'FromServer::Rejected' carrying that
+//! number. A 'SequenceTracker' spots messages the client missed.
+//!
+//! Files are shared with 'upload', and fetched with 'download'; the server
+//! sends a fetched file back as packets to collect with an
+//! 'attachment::Download'.
+
+use crate::attachment::{self, CHUNK_SIZE};
+use crate::codec::{self, Codec, PacketStream};
+use crate::protocol::{self, Agreement};
+use crate::runtime::TcpStream;
+use crate::tls::{self, TlsConnector};
+use crate::utils::{ChatResult, ChatStream};
+use crate::{Credential, FromClient, FromServer};
+use futures::io::{BufReader, WriteHalf};
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// How to connect to the server.
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// The codec to ask the server for. It may choose JSON instead.
+    pub codec: Codec,
+    /// The connector to use, and the name to expect on the server's
+    /// certificate, if connecting over TLS.
+    pub tls: Option<(TlsConnector, String)>,
+    /// The capabilities to offer in the 'Hello' exchange.
+    pub capabilities: Vec<String>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> ClientOptions {
+        ClientOptions {
+            codec: Codec::Json,
+            tls: None,
+            capabilities: protocol::CAPABILITIES
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// A connection to a chat server.
+pub struct ChatClient {
+    sender: ChatSender,
+    events: PacketStream<FromServer>,
+    agreement: Agreement,
+}
+
+/// The sending half of a 'ChatClient'.
+pub struct ChatSender {
+    writer: WriteHalf<Box<dyn ChatStream>>,
+    codec: Codec,
+    /// The id for the next post.
+    next_id: u64,
+    /// The number for the next upload.
+    next_upload: u64,
+}
+
+impl ChatClient {
+    /// Connect to the server at 'address' over plain TCP, using JSON.
+    pub async fn connect(address: &str) -> ChatResult<ChatClient> {
+        ChatClient::connect_with(address, &ClientOptions::default()).await
+    }
+
+    /// Connect to the server at 'address', and agree on a protocol version
+    /// and capabilities with it.
+    pub async fn connect_with(address: &str, options: &ClientOptions) -> ChatResult<ChatClient> {
+        let socket = TcpStream::connect(address).await?;
+        socket.set_nodelay(true)?;
+
+        let mut stream: Box<dyn ChatStream> = match &options.tls {
+            Some((connector, name)) => {
+                Box::new(connector.connect(tls::server_name(name)?, socket).await?)
+            }
+            None => Box::new(socket),
+        };
+        let codec = codec::request_codec(&mut stream, options.codec).await?;
+
+        let (reader, writer) = stream.split();
+        let mut sender = ChatSender {
+            writer,
+            codec,
+            next_id: 1,
+            next_upload: 1,
+        };
+        let mut events = codec.receive(BufReader::new(reader));
+
+        sender
+            .send(&FromClient::Hello {
+                version: protocol::VERSION,
+                capabilities: options.capabilities.clone(),
+            })
+            .await?;
+        let agreement = match next_reply(&mut events).await? {
+            FromServer::Hello {
+                version,
+                capabilities,
+            } => Agreement {
+                version,
+                capabilities,
+            },
+            FromServer::Error(message) => return Err(message.into()),
+            reply => return Err(format!("Unexpected reply to 'Hello': {reply:?}").into()),
+        };
+
+        Ok(ChatClient {
+            sender,
+            events,
+            agreement,
+        })
+    }
+
+    /// The protocol version and capabilities agreed with the server.
+    pub fn agreement(&self) -> &Agreement {
+        &self.agreement
+    }
+
+    /// Log in as 'nickname', waiting for the server to accept it. If the
+    /// server refuses, return its explanation as the error; the connection
+    /// stays open, so the caller may try again.
+    pub async fn login(
+        &mut self,
+        nickname: &str,
+        credential: Option<Credential>,
+    ) -> ChatResult<()> {
+        self.sender
+            .send(&FromClient::Login {
+                nickname: Arc::new(nickname.to_string()),
+                credential,
+            })
+            .await?;
+
+        loop {
+            match next_reply(&mut self.events).await? {
+                FromServer::LoggedIn { .. } => return Ok(()),
+                FromServer::Error(message) => return Err(message.into()),
+                // Nothing else is expected before logging in, but
+                // there's no harm in it either.
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn join(&mut self, group_name: &str) -> ChatResult<()> {
+        self.sender.join(group_name).await
+    }
+
+    /// Post 'message' to 'group_name', returning the id the server will
+    /// acknowledge it by.
+    pub async fn post(&mut self, group_name: &str, message: &str) -> ChatResult<u64> {
+        self.sender.post(group_name, message).await
+    }
+
+    /// Share the file 'file_name', with contents 'data', in 'group_name',
+    /// along with 'message'.
+    pub async fn upload(
+        &mut self,
+        group_name: &str,
+        file_name: &str,
+        message: &str,
+        data: &[u8],
+    ) -> ChatResult<()> {
+        self.sender
+            .upload(group_name, file_name, message, data)
+            .await
+    }
+
+    /// Ask for the attachment 'id'.
+    pub async fn download(&mut self, id: &str) -> ChatResult<()> {
+        self.sender.download(id).await
+    }
+
+    /// Send any request to the server.
+    pub async fn send(&mut self, request: &FromClient) -> ChatResult<()> {
+        self.sender.send(request).await
+    }
+
+    /// Separate the client into a sender and the stream of packets from the
+    /// server, so each can be used from its own task.
+    pub fn split(self) -> (ChatSender, PacketStream<FromServer>) {
+        (self.sender, self.events)
+    }
+}
+
+impl Stream for ChatClient {
+    type Item = ChatResult<FromServer>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+impl ChatSender {
+    pub async fn join(&mut self, group_name: &str) -> ChatResult<()> {
+        let group_name = Arc::new(group_name.to_string());
+        self.send(&FromClient::Join { group_name }).await
+    }
+
+    /// Post 'message' to 'group_name', returning the id the server will
+    /// acknowledge it by.
+    pub async fn post(&mut self, group_name: &str, message: &str) -> ChatResult<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&FromClient::Post {
+            group_name: Arc::new(group_name.to_string()),
+            message: Arc::new(message.to_string()),
+            id: Some(id),
+        })
+        .await?;
+        Ok(id)
+    }
+
+    /// Share the file 'file_name', with contents 'data', in 'group_name',
+    /// along with 'message'. The server reports any problem with an
+    /// 'Error'; once the whole file has arrived intact, it's posted like
+    /// any other message.
+    pub async fn upload(
+        &mut self,
+        group_name: &str,
+        file_name: &str,
+        message: &str,
+        data: &[u8],
+    ) -> ChatResult<()> {
+        let upload = self.next_upload;
+        self.next_upload += 1;
+
+        self.send(&FromClient::Offer {
+            upload,
+            group_name: Arc::new(group_name.to_string()),
+            file_name: file_name.to_string(),
+            size: data.len() as u64,
+            id: attachment::digest(data),
+            message: Arc::new(message.to_string()),
+        })
+        .await?;
+        for piece in data.chunks(CHUNK_SIZE) {
+            let data = attachment::encode(piece);
+            self.send(&FromClient::Chunk { upload, data }).await?;
+        }
+        self.send(&FromClient::Complete { upload }).await
+    }
+
+    /// Ask for the attachment 'id'.
+    pub async fn download(&mut self, id: &str) -> ChatResult<()> {
+        let id = id.to_string();
+        self.send(&FromClient::Download { id }).await
+    }
+
+    /// Send any request to the server.
+    pub async fn send(&mut self, request: &FromClient) -> ChatResult<()> {
+        self.codec.send(&mut self.writer, request).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Finish sending, telling the server there are no more requests. Over
+    /// TLS, this first sends the 'close_notify' alert the server expects
+    /// before the connection ends.
+    pub async fn close(mut self) -> ChatResult<()> {
+        futures::io::AsyncWriteExt::close(&mut self.writer).await?;
+        Ok(())
+    }
+}
+
+/// Follows the sequence numbers of each group's messages, to notice when
+/// some never arrived.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    latest: HashMap<Arc<String>, u64>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker::default()
+    }
+
+    /// Note a message numbered 'seq' in 'group_name'. If messages were
+    /// missed since the last one noted, return how many.
+    ///
+    /// The first message noted for a group sets where counting starts, and
+    /// messages at or before the latest, such as history replayed after a
+    /// reconnection, are ignored.
+    pub fn observe(&mut self, group_name: &Arc<String>, seq: u64) -> Option<u64> {
+        if seq == 0 {
+            return None;
+        }
+
+        let latest = self.latest.entry(group_name.clone()).or_insert(seq - 1);
+        let missed = seq.checked_sub(*latest + 1).filter(|&missed| missed > 0);
+        *latest = (*latest).max(seq);
+        missed
+    }
+
+    /// Stop following 'group_name', after leaving it.
+    pub fn forget(&mut self, group_name: &String) {
+        self.latest.remove(group_name);
+    }
+}
+
+async fn next_reply(events: &mut PacketStream<FromServer>) -> ChatResult<FromServer> {
+    match events.next().await {
+        Some(reply) => reply,
+        None => Err("The server closed the connection".into()),
+    }
+}
+
+#[test]
+fn test_chat_client() {
+    use crate::runtime::{self, TcpListener};
+    use crate::utils;
+
+    let name = |text: &str| Arc::new(text.to_string());
+
+    runtime::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // A server that refuses the first login, then accepts anything.
+        let server = runtime::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (reader, mut socket) = socket.split();
+            let mut requests = utils::receive_as_json(BufReader::new(reader));
+            let mut received = Vec::new();
+
+            while let Some(request) = requests.next().await {
+                let request: FromClient = request.unwrap();
+                let reply = match &request {
+                    FromClient::Hello { .. } => FromServer::Hello {
+                        version: protocol::VERSION,
+                        capabilities: vec![protocol::HISTORY.to_string()],
+                    },
+                    FromClient::Login { nickname, .. } if received.len() == 1 => {
+                        FromServer::Error(format!("Nickname '{nickname}' is in use"))
+                    }
+                    FromClient::Login { nickname, .. } => FromServer::LoggedIn {
+                        nickname: nickname.clone(),
+                    },
+                    FromClient::Post { id: Some(id), .. } => FromServer::Ack { id: *id },
+                    _ => FromServer::Pong,
+                };
+                received.push(request);
+                utils::send_as_json(&mut socket, &reply).await.unwrap();
+            }
+
+            received
+        });
+
+        let mut client = ChatClient::connect(&address).await.unwrap();
+        assert_eq!(client.agreement().version, protocol::VERSION);
+        assert!(client.agreement().supports(protocol::HISTORY));
+        assert!(!client.agreement().supports(protocol::MODERATION));
+
+        let error = client.login("jimb", None).await.unwrap_err();
+        assert_eq!(error.to_string(), "Nickname 'jimb' is in use");
+        client.login("jimb2", None).await.unwrap();
+
+        client.join("Dogs").await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), FromServer::Pong);
+
+        // Each post gets a new id, for the server to acknowledge it by.
+        let (mut sender, mut events) = client.split();
+        assert_eq!(sender.post("Dogs", "woof").await.unwrap(), 1);
+        assert_eq!(sender.post("Dogs", "arf").await.unwrap(), 2);
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            FromServer::Ack { id: 1 }
+        );
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            FromServer::Ack { id: 2 }
+        );
+
+        sender.close().await.unwrap();
+        drop(events);
+        let received = server.await;
+        assert_eq!(received.len(), 6);
+        assert_eq!(
+            received[3],
+            FromClient::Join {
+                group_name: name("Dogs")
+            }
+        );
+    });
+
+    let mut tracker = SequenceTracker::new();
+    let dogs = name("Dogs");
+    assert_eq!(tracker.observe(&dogs, 7), None);
+    assert_eq!(tracker.observe(&dogs, 8), None);
+    assert_eq!(tracker.observe(&dogs, 6), None);
+    assert_eq!(tracker.observe(&dogs, 12), Some(3));
+    assert_eq!(tracker.observe(&dogs, 0), None);
+    tracker.forget(&dogs);
+    assert_eq!(tracker.observe(&dogs, 20), None);
+}
@@ -0,0 +1,275 @@
+//! How packets are encoded on a connection.
+//!
+//! Connections use newline-delimited JSON unless the client asks otherwise.
+//! A client that wants another codec starts the connection by sending
+//! 'PREAMBLE' followed by the codec's id byte, before any packets. The server
+//! answers with 'PREAMBLE' and the id of the codec it will actually use, which
+//! is JSON if it doesn't know the one requested. JSON text never starts with
+//! a zero byte, so the server can tell the two kinds of client apart.
+
+use crate::utils::{self, ChatResult};
+use futures::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::pin::Pin;
+use std::str::FromStr;
+
+pub const PREAMBLE: &[u8; 5] = b"\0chat";
+
+/// The largest frame body 'Codec::MessagePack' will send or accept, and the
+/// longest line 'Codec::Json' will accept, in bytes.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+pub type PacketStream<P> = Pin<Box<dyn Stream<Item = ChatResult<P>> + Send>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// One JSON packet per line.
+    Json,
+    /// Each packet is a MessagePack body, preceded by its length as a
+    /// four-byte big-endian integer.
+    MessagePack,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Json => b'j',
+            Codec::MessagePack => b'm',
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            b'j' => Some(Codec::Json),
+            b'm' => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub async fn send<S, P>(self, outbound: &mut S, packet: &P) -> ChatResult<()>
+    where
+        S: AsyncWrite + Unpin,
+        P: Serialize,
+    {
+        match self {
+            Codec::Json => utils::send_as_json(outbound, packet).await,
+            Codec::MessagePack => send_as_frame(outbound, packet).await,
+        }
+    }
+
+    pub fn receive<S, P>(self, inbound: S) -> PacketStream<P>
+    where
+        S: AsyncBufRead + Send + Unpin + 'static,
+        P: DeserializeOwned + Send + 'static,
+    {
+        match self {
+            Codec::Json => Box::pin(utils::receive_as_json(inbound)),
+            Codec::MessagePack => Box::pin(receive_as_frames(inbound)),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Codec, String> {
+        match name {
+            "json" => Ok(Codec::Json),
+            "msgpack" => Ok(Codec::MessagePack),
+            _ => Err(format!("Unknown codec '{name}'")),
+        }
+    }
+}
+
+/// Ask the server at the other end of 'stream' to use 'codec'.
+/// Return the codec the server agreed to.
+pub async fn request_codec<S>(stream: &mut S, codec: Codec) -> ChatResult<Codec>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if codec == Codec::Json {
+        return Ok(Codec::Json);
+    }
+
+    stream.write_all(PREAMBLE).await?;
+    stream.write_all(&[codec.id()]).await?;
+    stream.flush().await?;
+
+    let mut reply = [0; PREAMBLE.len() + 1];
+    stream.read_exact(&mut reply).await?;
+
+    if &reply[..PREAMBLE.len()] != PREAMBLE {
+        return Err("Server did not answer the codec request".into());
+    }
+
+    Codec::from_id(reply[PREAMBLE.len()]).ok_or_else(|| "Server chose an unknown codec".into())
+}
+
+/// Find out which codec the client on 'inbound' wants, answering on
+/// 'outbound' if it asked for one.
+pub async fn accept_codec<R, W>(inbound: &mut R, outbound: &mut W) -> ChatResult<Codec>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buffered = futures::io::AsyncBufReadExt::fill_buf(inbound).await?;
+    if buffered.first() != Some(&0) {
+        return Ok(Codec::Json);
+    }
+
+    let mut request = [0; PREAMBLE.len() + 1];
+    inbound.read_exact(&mut request).await?;
+
+    if &request[..PREAMBLE.len()] != PREAMBLE {
+        return Err("Unrecognized connection preamble".into());
+    }
+
+    let codec = Codec::from_id(request[PREAMBLE.len()]).unwrap_or(Codec::Json);
+
+    outbound.write_all(PREAMBLE).await?;
+    outbound.write_all(&[codec.id()]).await?;
+    outbound.flush().await?;
+
+    Ok(codec)
+}
+
+pub async fn send_as_frame<S, P>(outbound: &mut S, packet: &P) -> ChatResult<()>
+where
+    S: AsyncWrite + Unpin,
+    P: Serialize,
+{
+    let body = rmp_serde::to_vec_named(packet)?;
+
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(format!("Packet of {} bytes is too large to send", body.len()).into());
+    }
+
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+
+    outbound.write_all(&frame).await?;
+
+    Ok(())
+}
+
+/// Return a stream of the packets framed by 'send_as_frame' on 'inbound'.
+/// The stream ends after the first error, since the framing can't be trusted
+/// from then on.
+pub fn receive_as_frames<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
+where
+    S: AsyncRead + Unpin,
+    P: DeserializeOwned,
+{
+    futures::stream::unfold(Some(inbound), |inbound| async move {
+        let mut inbound = inbound?;
+
+        match read_frame(&mut inbound).await {
+            Ok(Some(packet)) => Some((Ok(packet), Some(inbound))),
+            Ok(None) => None,
+            Err(error) => Some((Err(error), None)),
+        }
+    })
+}
+
+/// Read one frame from 'inbound', or return 'None' if the stream ends cleanly
+/// before the next one begins.
+async fn read_frame<S, P>(inbound: &mut S) -> ChatResult<Option<P>>
+where
+    S: AsyncRead + Unpin,
+    P: DeserializeOwned,
+{
+    let mut header = [0; 4];
+    let mut filled = 0;
+
+    while filled < header.len() {
+        let n = inbound.read(&mut header[filled..]).await?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err("Connection closed in the middle of a frame".into());
+        }
+        filled += n;
+    }
+
+    let length = u32::from_be_bytes(header) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(
+            format!("Frame of {length} bytes exceeds the limit of {MAX_FRAME_SIZE}").into(),
+        );
+    }
+
+    let mut body = vec![0; length];
+    inbound.read_exact(&mut body).await?;
+
+    Ok(Some(rmp_serde::from_slice(&body)?))
+}
+
+#[test]
+fn test_frame_round_trip_and_size_limit() {
+    use crate::FromClient;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+    use std::sync::Arc;
+
+    let request = FromClient::Post {
+        group_name: Arc::new("Dogs".to_string()),
+        message: Arc::new("Samoyeds rock!\nSo fluffy.".to_string()),
+        id: Some(7),
+    };
+
+    block_on(async {
+        let mut buffer = Vec::new();
+        send_as_frame(&mut buffer, &request).await.unwrap();
+        send_as_frame(&mut buffer, &FromClient::ListGroups)
+            .await
+            .unwrap();
+
+        let received: Vec<FromClient> = receive_as_frames(Cursor::new(buffer))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(received, vec![request, FromClient::ListGroups]);
+
+        let oversized = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        let mut frames = Box::pin(receive_as_frames::<_, FromClient>(Cursor::new(oversized)));
+        assert!(frames.next().await.unwrap().is_err());
+        assert!(frames.next().await.is_none());
+    });
+}
+
+#[test]
+fn test_codec_negotiation() {
+    use futures::executor::block_on;
+    use futures::io::{BufReader, Cursor};
+
+    block_on(async {
+        // The client's side of the exchange, replayed against a buffer.
+        let mut request = Vec::new();
+        request.extend_from_slice(PREAMBLE);
+        request.push(Codec::MessagePack.id());
+        request.extend_from_slice(b"rest of the stream");
+
+        let mut inbound = BufReader::new(Cursor::new(request));
+        let mut reply = Vec::new();
+        let codec = accept_codec(&mut inbound, &mut reply).await.unwrap();
+
+        assert_eq!(codec, Codec::MessagePack);
+        assert_eq!(&reply[..PREAMBLE.len()], PREAMBLE);
+        assert_eq!(reply[PREAMBLE.len()], Codec::MessagePack.id());
+
+        let mut rest = String::new();
+        inbound.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "rest of the stream");
+
+        // A plain JSON client sends nothing extra, and gets no reply.
+        let mut inbound = BufReader::new(Cursor::new(b"{\"ListGroups\":null}\n".to_vec()));
+        let mut reply = Vec::new();
+        let codec = accept_codec(&mut inbound, &mut reply).await.unwrap();
+
+        assert_eq!(codec, Codec::Json);
+        assert!(reply.is_empty());
+    });
+}
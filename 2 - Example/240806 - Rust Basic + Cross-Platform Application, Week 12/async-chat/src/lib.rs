@@ -1,28 +1,381 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+pub mod attachment;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub mod client;
+pub mod codec;
+pub mod protocol;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub mod runtime;
+pub mod tls;
 pub mod utils;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum FromClient {
+    /// Offer a protocol version and capabilities. If sent at all, this must
+    /// be the first packet on the connection.
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// Claim 'nickname'. Servers that keep accounts also need 'credential'.
+    Login {
+        nickname: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credential: Option<Credential>,
+    },
     Join {
         group_name: Arc<String>,
     },
+    Leave {
+        group_name: Arc<String>,
+    },
+    /// Post 'message' to 'group_name'. If the connection negotiated 'acks',
+    /// a post with an 'id' chosen by the client is answered with
+    /// 'FromServer::Ack' or 'FromServer::Rejected' carrying the same 'id'.
     Post {
         group_name: Arc<String>,
         message: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// Send 'message' to the user logged in as 'to', and no one else.
+    DirectMessage {
+        to: Arc<String>,
+        message: Arc<String>,
+    },
+    ListGroups,
+    ListMembers {
+        group_name: Arc<String>,
+    },
+    /// Ask for up to 'limit' of the most recent messages in 'group_name'
+    /// posted before the timestamp 'before' (or before now, if 'None').
+    History {
+        group_name: Arc<String>,
+        before: Option<u64>,
+        limit: Option<usize>,
+    },
+    /// Ask the server to answer with 'FromServer::Pong', to show that both
+    /// ends of the connection are still alive.
+    Ping,
+    /// Make a new group owned by the sender, who joins it at once. A
+    /// 'private' group can only be joined by invitation.
+    Create {
+        group_name: Arc<String>,
+        private: bool,
+    },
+    /// Let 'nickname' join 'group_name', even if it is private or they were
+    /// banned from it. Moderators only.
+    Invite {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Remove 'nickname' from 'group_name'. Moderators only.
+    Kick {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Remove 'nickname' from 'group_name' and keep them out until they are
+    /// invited back. Moderators only.
+    Ban {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+    },
+    /// Make 'nickname' a moderator of 'group_name', or an ordinary member
+    /// again. The group's owner only.
+    SetRole {
+        group_name: Arc<String>,
+        nickname: Arc<String>,
+        role: Role,
+    },
+    /// Begin upload number 'upload', chosen by the client, of a file to
+    /// share in 'group_name'. 'size' is the file's length in bytes, and
+    /// 'id' its SHA-256 digest; see the 'attachment' module. The contents
+    /// follow in 'Chunk's.
+    Offer {
+        upload: u64,
+        group_name: Arc<String>,
+        file_name: String,
+        size: u64,
+        id: String,
+        message: Arc<String>,
+    },
+    /// The next piece of upload 'upload', base64-encoded.
+    Chunk {
+        upload: u64,
+        data: String,
+    },
+    /// Finish upload 'upload', posting its 'message' to the group with the
+    /// file attached.
+    Complete {
+        upload: u64,
+    },
+    /// Ask for the attachment 'id'. The server answers with
+    /// 'FromServer::Offer', then 'Chunk's, then 'Complete'.
+    Download {
+        id: String,
+    },
+    /// Replace the text of message 'seq' in 'group_name'. Only the message's
+    /// sender may.
+    Edit {
+        group_name: Arc<String>,
+        seq: u64,
+        message: Arc<String>,
+    },
+    /// Delete message 'seq' in 'group_name'. Its sender and the group's
+    /// moderators may.
+    Delete {
+        group_name: Arc<String>,
+        seq: u64,
+    },
+    /// React to message 'seq' in 'group_name' with 'reaction', such as an
+    /// emoji, or take the reaction back if the sender already gave it.
+    React {
+        group_name: Arc<String>,
+        seq: u64,
+        reaction: Arc<String>,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+/// A user's standing in a group.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Role {
+    Member,
+    /// May invite, kick and ban members.
+    Moderator,
+    /// May also appoint and remove moderators.
+    Owner,
+}
+
+/// Proof that the client may use a nickname, for servers that keep accounts.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Credential {
+    Password(String),
+    /// A secret issued to the user by the server's administrator, for bots
+    /// and other clients that shouldn't hold a password.
+    Token(String),
+}
+
+/// A message as posted to a group.
+/// 'timestamp' is in milliseconds since the Unix epoch.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PostedMessage {
+    pub sender: Arc<String>,
+    pub message: Arc<String>,
+    pub timestamp: u64,
+    /// The message's place in its group: one more than the message posted
+    /// before it on the same server. Zero for messages saved before
+    /// sequence numbers existed. This is also the id by which the message is
+    /// edited, deleted and reacted to.
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    /// Whether the sender has changed 'message' since posting it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    /// Whether the message has been deleted. It keeps its place in the
+    /// group, but its text, attachment and reactions are gone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: Reactions,
+}
+
+/// Each reaction given to a message, with the nicknames of those who gave
+/// it, in the order they did.
+pub type Reactions = BTreeMap<Arc<String>, Vec<Arc<String>>>;
+
+/// A file shared along with a message.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Attachment {
+    /// The SHA-256 digest of the file's contents, in lowercase hex.
+    pub id: String,
+    pub file_name: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum FromServer {
+    /// The reply to 'FromClient::Hello': the version and capabilities the
+    /// connection will use.
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    LoggedIn {
+        nickname: Arc<String>,
+    },
+    /// A message posted to 'group_name'. A jump in 'seq' means the client
+    /// missed messages in between; see 'PostedMessage::seq'. Messages
+    /// replayed from the group's history show any changes made since they
+    /// were posted.
     Message {
         group_name: Arc<String>,
+        sender: Arc<String>,
+        message: Arc<String>,
+        timestamp: u64,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachment: Option<Attachment>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        edited: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        deleted: bool,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        reactions: Reactions,
+    },
+    /// A private message sent to this user alone by 'from'.
+    DirectMessage {
+        from: Arc<String>,
         message: Arc<String>,
     },
+    /// Sent after a new member of 'group_name' has been sent the group's
+    /// recent history; every 'Message' that follows is live.
+    HistoryEnd {
+        group_name: Arc<String>,
+    },
+    /// The reply to a 'FromClient::History' request, oldest message first.
+    History {
+        group_name: Arc<String>,
+        messages: Vec<PostedMessage>,
+    },
+    Groups {
+        group_names: Vec<Arc<String>>,
+    },
+    Members {
+        group_name: Arc<String>,
+        nicknames: Vec<Arc<String>>,
+    },
+    /// The reply to 'FromClient::Ping'.
+    Pong,
+    /// 'by' has invited this user to join 'group_name'.
+    Invited {
+        group_name: Arc<String>,
+        by: Arc<String>,
+    },
+    /// 'by' has removed this user from 'group_name', and banned them too if
+    /// 'banned' is true.
+    Removed {
+        group_name: Arc<String>,
+        by: Arc<String>,
+        banned: bool,
+    },
+    /// The post numbered 'id' was accepted, and sent to the group's members.
+    Ack {
+        id: u64,
+    },
+    /// The post numbered 'id' was refused, for 'reason'.
+    Rejected {
+        id: u64,
+        reason: String,
+    },
+    /// The start of a download requested with 'FromClient::Download'.
+    Offer {
+        attachment: Attachment,
+    },
+    /// The next piece of the attachment 'id', base64-encoded.
+    Chunk {
+        id: String,
+        data: String,
+    },
+    /// The end of the attachment 'id'.
+    Complete {
+        id: String,
+    },
+    /// Message 'seq' in 'group_name' now reads 'message'.
+    Edited {
+        group_name: Arc<String>,
+        seq: u64,
+        message: Arc<String>,
+    },
+    /// 'by' deleted message 'seq' in 'group_name'.
+    Deleted {
+        group_name: Arc<String>,
+        seq: u64,
+        by: Arc<String>,
+    },
+    /// 'nickname' reacted to message 'seq' in 'group_name' with 'reaction',
+    /// or took that reaction back if 'added' is false.
+    Reacted {
+        group_name: Arc<String>,
+        seq: u64,
+        nickname: Arc<String>,
+        reaction: Arc<String>,
+        added: bool,
+    },
     Error(String),
 }
 
+/// Packets exchanged between federated servers, in either direction. Links
+/// use the same newline-delimited JSON as clients.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum FromPeer {
+    /// Introduce the sending server by its node name. This must be the first
+    /// packet on a link.
+    Hello { node: Arc<String> },
+    /// A message posted to 'group_name' on the server named 'origin', which
+    /// numbered it 'id'. Servers pass these on to their other links, and use
+    /// 'origin', 'epoch' and 'id' to recognize one they've seen before.
+    /// 'epoch' is chosen afresh each time 'origin' starts, since its
+    /// numbering starts over too.
+    Post {
+        origin: Arc<String>,
+        epoch: u64,
+        id: u64,
+        group_name: Arc<String>,
+        sender: Arc<String>,
+        message: Arc<String>,
+    },
+}
+
+impl FromClient {
+    /// Return the capability the connection needs for this request, if any.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            FromClient::History { .. } => Some(protocol::HISTORY),
+            FromClient::DirectMessage { .. } => Some(protocol::DIRECT_MESSAGES),
+            FromClient::Ping => Some(protocol::HEARTBEAT),
+            FromClient::Create { .. }
+            | FromClient::Invite { .. }
+            | FromClient::Kick { .. }
+            | FromClient::Ban { .. }
+            | FromClient::SetRole { .. } => Some(protocol::MODERATION),
+            FromClient::Offer { .. }
+            | FromClient::Chunk { .. }
+            | FromClient::Complete { .. }
+            | FromClient::Download { .. } => Some(protocol::ATTACHMENTS),
+            FromClient::Edit { .. } | FromClient::Delete { .. } | FromClient::React { .. } => {
+                Some(protocol::EDITS)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromServer {
+    /// Return the capability a client needs to understand this packet, if any.
+    pub fn capability(&self) -> Option<&'static str> {
+        match self {
+            FromServer::HistoryEnd { .. } | FromServer::History { .. } => Some(protocol::HISTORY),
+            FromServer::DirectMessage { .. } => Some(protocol::DIRECT_MESSAGES),
+            FromServer::Pong => Some(protocol::HEARTBEAT),
+            FromServer::Invited { .. } | FromServer::Removed { .. } => Some(protocol::MODERATION),
+            FromServer::Ack { .. } | FromServer::Rejected { .. } => Some(protocol::ACKS),
+            FromServer::Offer { .. } | FromServer::Chunk { .. } | FromServer::Complete { .. } => {
+                Some(protocol::ATTACHMENTS)
+            }
+            FromServer::Edited { .. } | FromServer::Deleted { .. } | FromServer::Reacted { .. } => {
+                Some(protocol::EDITS)
+            }
+            _ => None,
+        }
+    }
+}
+
 #[test]
 fn test_from_client_json() {
     use std::sync::Arc;
@@ -30,6 +383,7 @@ fn test_from_client_json() {
     let from_client = FromClient::Post {
         group_name: Arc::new("Dogs".to_string()),
         message: Arc::new("Samoyeds rock!".to_string()),
+        id: None,
     };
 
     let json = serde_json::to_string(&from_client).unwrap();
@@ -43,3 +397,36 @@ fn test_from_client_json() {
         from_client
     );
 }
+
+#[test]
+fn test_from_server_json() {
+    use std::sync::Arc;
+
+    let from_server = FromServer::Message {
+        group_name: Arc::new("Dogs".to_string()),
+        sender: Arc::new("jimb".to_string()),
+        message: Arc::new("Samoyeds rock!".to_string()),
+        timestamp: 1_722_902_400_000,
+        seq: 7,
+        attachment: None,
+        edited: false,
+        deleted: false,
+        reactions: Reactions::new(),
+    };
+
+    let json = serde_json::to_string(&from_server).unwrap();
+
+    assert_eq!(
+        json,
+        r#"{"Message":{"group_name":"Dogs","sender":"jimb","message":"Samoyeds rock!","timestamp":1722902400000,"seq":7}}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<FromServer>(&json).unwrap(),
+        from_server
+    );
+
+    // Servers from before sequence numbers left them out.
+    let old_json = json.replace(r#","seq":7"#, "");
+    let old = serde_json::from_str::<FromServer>(&old_json).unwrap();
+    assert!(matches!(old, FromServer::Message { seq: 0, .. }));
+}
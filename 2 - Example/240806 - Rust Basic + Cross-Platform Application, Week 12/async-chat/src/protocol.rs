@@ -0,0 +1,115 @@
+//! Protocol versions and optional capabilities.
+//!
+//! A client opens with 'FromClient::Hello', giving the newest protocol
+//! version it speaks and the optional capabilities it understands. The server
+//! answers with 'FromServer::Hello', giving the version both sides will use
+//! and the capabilities they share, and from then on sends nothing that needs
+//! a capability the client didn't list.
+//!
+//! Clients written before 'Hello' existed go straight to 'Login'; the server
+//! treats them as speaking version 1 with 'LEGACY_CAPABILITIES'.
+
+/// The newest protocol version this crate speaks.
+pub const VERSION: u32 = 2;
+
+/// The oldest protocol version this crate still accepts.
+pub const MIN_VERSION: u32 = 1;
+
+/// Group history replay on join, and 'History' requests.
+pub const HISTORY: &str = "history";
+
+/// Private messages between users.
+pub const DIRECT_MESSAGES: &str = "direct-messages";
+
+/// Group owners and moderators, private groups, and the requests that
+/// manage them.
+pub const MODERATION: &str = "moderation";
+
+/// 'Ping' and 'Pong'. The server may close any connection that sends nothing
+/// for as long as its idle timeout, so a client that may go quiet should ask
+/// for this and ping when it has nothing else to say.
+pub const HEARTBEAT: &str = "heartbeat";
+
+/// 'Ack' and 'Rejected' replies to posts that carry an 'id'.
+pub const ACKS: &str = "acks";
+
+/// Uploading and downloading files attached to group messages.
+pub const ATTACHMENTS: &str = "attachments";
+
+/// Editing, deleting and reacting to group messages, and hearing when others
+/// do. Clients without this still see the changes in replayed history.
+pub const EDITS: &str = "edits";
+
+/// Every capability this crate supports.
+pub const CAPABILITIES: &[&str] = &[
+    HISTORY,
+    DIRECT_MESSAGES,
+    MODERATION,
+    HEARTBEAT,
+    ACKS,
+    ATTACHMENTS,
+    EDITS,
+];
+
+/// The capabilities a version 1 client understands without saying so.
+/// New capabilities must not be added here.
+pub const LEGACY_CAPABILITIES: &[&str] = &[HISTORY, DIRECT_MESSAGES];
+
+/// The terms a connection runs under, once the 'Hello' exchange is over.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Agreement {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Agreement {
+    /// The terms for a client that never sent 'Hello'.
+    pub fn legacy() -> Agreement {
+        Agreement {
+            version: 1,
+            capabilities: LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Work out the terms for a peer that offered 'version' and 'capabilities':
+/// the older of the two versions, and the capabilities both sides support.
+/// Return an error explaining the problem if the peer is too old.
+pub fn negotiate(version: u32, capabilities: &[String]) -> Result<Agreement, String> {
+    if version < MIN_VERSION {
+        return Err(format!(
+            "Protocol version {version} is not supported; \
+             this server speaks versions {MIN_VERSION} to {VERSION}"
+        ));
+    }
+
+    let capabilities = capabilities
+        .iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .cloned()
+        .collect();
+
+    Ok(Agreement {
+        version: version.min(VERSION),
+        capabilities,
+    })
+}
+
+#[test]
+fn test_negotiate() {
+    let offered = vec![HISTORY.to_string(), "teleportation".to_string()];
+
+    assert_eq!(
+        negotiate(VERSION + 1, &offered),
+        Ok(Agreement {
+            version: VERSION,
+            capabilities: vec![HISTORY.to_string()],
+        })
+    );
+    assert_eq!(negotiate(1, &[]).unwrap().version, 1);
+    assert!(negotiate(0, &offered).is_err());
+}
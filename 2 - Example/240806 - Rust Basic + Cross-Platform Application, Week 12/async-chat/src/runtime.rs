@@ -0,0 +1,330 @@
+//! The async runtime the client and server run on.
+//!
+//! The protocol itself, in 'utils', 'codec' and the packet types, needs
+//! nothing but the 'futures' traits, so it works on any runtime. Spawning
+//! tasks, waiting, and opening sockets do need one, and this module provides
+//! them: from tokio with the 'runtime-tokio' feature, which is the default,
+//! or from async-std with 'runtime-async-std'. If both are enabled, tokio is
+//! used.
+//!
+//! Either way, tasks run on one runtime shared by the whole process, so
+//! 'spawn' works from anywhere, not only from inside another task.
+
+use futures_lite::FutureExt;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+#[cfg(feature = "runtime-tokio")]
+pub use self::tokio_backend::{
+    block_on, sleep, spawn, spawn_blocking, JoinHandle, TcpListener, TcpStream,
+};
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+pub use self::async_std_backend::{
+    block_on, sleep, spawn, spawn_blocking, JoinHandle, TcpListener, TcpStream,
+};
+
+/// The error from 'timeout' when the time runs out.
+#[derive(Debug, PartialEq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Run 'future', giving up if it hasn't finished after 'duration'.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, TimedOut> {
+    let finished = async { Ok(future.await) };
+    let expired = async {
+        sleep(duration).await;
+        Err(TimedOut)
+    };
+
+    finished.race(expired).await
+}
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_backend {
+    use futures::io::{AsyncRead, AsyncWrite};
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::OnceLock;
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncRead as _, AsyncWrite as _, ReadBuf};
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+        RUNTIME.get_or_init(|| {
+            Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("could not start the tokio runtime")
+        })
+    }
+
+    /// Run 'future' to completion, blocking the current thread.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        runtime().block_on(future)
+    }
+
+    /// Run 'future' on a task of its own.
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(runtime().spawn(future))
+    }
+
+    /// Run 'function' on a thread where blocking is allowed.
+    pub fn spawn_blocking<F, T>(function: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JoinHandle(runtime().spawn_blocking(function))
+    }
+
+    pub async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    /// Waits for a spawned task to finish. Dropping it lets the task run
+    /// on unobserved. If the task panicked, so does waiting for it.
+    pub struct JoinHandle<T>(tokio::task::JoinHandle<T>);
+
+    impl<T> Future for JoinHandle<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            match ready!(Pin::new(&mut self.0).poll(cx)) {
+                Ok(output) => Poll::Ready(output),
+                Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+                Err(error) => panic!("task failed: {error}"),
+            }
+        }
+    }
+
+    /// A TCP connection, implementing the 'futures' I/O traits.
+    pub struct TcpStream(tokio::net::TcpStream);
+
+    impl TcpStream {
+        pub async fn connect(address: &str) -> io::Result<TcpStream> {
+            tokio::net::TcpStream::connect(address).await.map(TcpStream)
+        }
+
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.0.set_nodelay(nodelay)
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    impl AsyncRead for TcpStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }
+    }
+
+    impl AsyncWrite for TcpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    pub struct TcpListener(tokio::net::TcpListener);
+
+    impl TcpListener {
+        pub async fn bind(address: &str) -> io::Result<TcpListener> {
+            tokio::net::TcpListener::bind(address)
+                .await
+                .map(TcpListener)
+        }
+
+        pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            let (socket, peer) = self.0.accept().await?;
+            Ok((TcpStream(socket), peer))
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+}
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod async_std_backend {
+    use futures::io::{AsyncRead, AsyncWrite};
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    pub use async_std::task::JoinHandle;
+
+    /// Run 'future' to completion, blocking the current thread.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+
+    /// Run 'future' on a task of its own.
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::spawn(future)
+    }
+
+    /// Run 'function' on a thread where blocking is allowed.
+    pub fn spawn_blocking<F, T>(function: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        async_std::task::spawn_blocking(function)
+    }
+
+    pub async fn sleep(duration: Duration) {
+        async_std::task::sleep(duration).await
+    }
+
+    /// A TCP connection, implementing the 'futures' I/O traits.
+    pub struct TcpStream(async_std::net::TcpStream);
+
+    impl TcpStream {
+        pub async fn connect(address: &str) -> io::Result<TcpStream> {
+            async_std::net::TcpStream::connect(address)
+                .await
+                .map(TcpStream)
+        }
+
+        pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.0.set_nodelay(nodelay)
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    impl AsyncRead for TcpStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for TcpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        // async-std's sockets only flush on close, but a closed stream
+        // should tell the other end there's no more to come, as tokio's do.
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.0.shutdown(std::net::Shutdown::Write))
+        }
+    }
+
+    pub struct TcpListener(async_std::net::TcpListener);
+
+    impl TcpListener {
+        pub async fn bind(address: &str) -> io::Result<TcpListener> {
+            async_std::net::TcpListener::bind(address)
+                .await
+                .map(TcpListener)
+        }
+
+        pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            let (socket, peer) = self.0.accept().await?;
+            Ok((TcpStream(socket), peer))
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+}
+
+#[test]
+fn test_runtime() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // Closing the client's stream ends what the server reads.
+        let server = spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut client = TcpStream::connect(&address).await.unwrap();
+        client.write_all(b"woof").await.unwrap();
+        client.close().await.unwrap();
+        assert_eq!(server.await, b"woof");
+
+        assert_eq!(spawn_blocking(|| 6 * 7).await, 42);
+        assert_eq!(timeout(Duration::from_secs(5), async { 1 }).await, Ok(1));
+        let never = futures::future::pending::<()>();
+        assert_eq!(
+            timeout(Duration::from_millis(10), never).await,
+            Err(TimedOut)
+        );
+    });
+}
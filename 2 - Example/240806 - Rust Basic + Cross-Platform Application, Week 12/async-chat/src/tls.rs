@@ -0,0 +1,109 @@
+//! Optional TLS for the chat connection.
+//!
+//! Both ends read PEM files: the server its certificate chain and private key,
+//! the client the certificate it should trust. For local testing a self-signed
+//! certificate works as both, for example one made with:
+//!
+//! ```text
+//! openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+//!     -keyout localhost.key -out localhost.pem -days 365 -subj /CN=localhost \
+//!     -addext subjectAltName=DNS:localhost,IP:127.0.0.1
+//! ```
+
+use crate::utils::ChatResult;
+use futures_rustls::pki_types::pem::PemObject;
+use futures_rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use futures_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::path::Path;
+use std::sync::Arc;
+
+pub use futures_rustls::{TlsAcceptor, TlsConnector};
+
+/// Build an acceptor that presents the certificate chain in 'cert_path',
+/// signed with the private key in 'key_path'.
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> ChatResult<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|error| format!("Could not read key from {}: {error}", key_path.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build a connector that trusts only the certificates in 'ca_path'.
+pub fn connector(ca_path: &Path) -> ChatResult<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(cert)?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Parse 'name' as the host name or IP address the server's certificate
+/// should be issued for.
+pub fn server_name(name: &str) -> ChatResult<ServerName<'static>> {
+    Ok(ServerName::try_from(name.to_string())?)
+}
+
+fn load_certs(path: &Path) -> ChatResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| {
+            format!(
+                "Could not read certificates from {}: {error}",
+                path.display()
+            )
+        })?;
+
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()).into());
+    }
+
+    Ok(certs)
+}
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+#[test]
+fn test_json_over_tls() {
+    use crate::runtime::{self, TcpListener, TcpStream};
+    use crate::utils;
+    use crate::FromClient;
+    use futures::io::BufReader;
+    use futures::prelude::*;
+
+    let certs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+    let acceptor = acceptor(&certs.join("localhost.pem"), &certs.join("localhost.key")).unwrap();
+    let connector = connector(&certs.join("localhost.pem")).unwrap();
+
+    runtime::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = runtime::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(socket).await.unwrap();
+            let mut from_client = utils::receive_as_json(BufReader::new(stream));
+
+            from_client.next().await.unwrap().unwrap()
+        });
+
+        let socket = TcpStream::connect(&address).await.unwrap();
+        let name = server_name("localhost").unwrap();
+        let mut stream = connector.connect(name, socket).await.unwrap();
+
+        let request = FromClient::ListGroups;
+        utils::send_as_json(&mut stream, &request).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let received: FromClient = server.await;
+        assert_eq!(received, request);
+    });
+}
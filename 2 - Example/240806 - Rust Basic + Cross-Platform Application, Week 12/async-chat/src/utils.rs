@@ -1,4 +1,5 @@
-use async_std::prelude::*;
+use crate::codec::MAX_FRAME_SIZE;
+use futures::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
@@ -7,9 +8,15 @@ use std::marker::Unpin;
 pub type ChatError = Box<dyn Error + Send + Sync + 'static>;
 pub type ChatResult<T> = Result<T, ChatError>;
 
+/// A two-way byte stream the chat protocol can run over, such as a plain
+/// 'TcpStream' or one wrapped in TLS.
+pub trait ChatStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> ChatStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
 pub async fn send_as_json<S, P>(outbound: &mut S, packet: &P) -> ChatResult<()>
 where
-    S: async_std::io::Write + Unpin,
+    S: AsyncWrite + Unpin,
     P: Serialize,
 {
     let mut json = serde_json::to_string(&packet)?;
@@ -20,15 +27,81 @@ where
     Ok(())
 }
 
+/// Return a stream of the packets sent by 'send_as_json' on 'inbound', one
+/// per line. A line longer than 'MAX_FRAME_SIZE' is an error, and ends the
+/// stream, rather than being buffered however long it grows.
 pub fn receive_as_json<S, P>(inbound: S) -> impl Stream<Item = ChatResult<P>>
 where
-    S: async_std::io::BufRead + Unpin,
+    S: AsyncBufRead + Unpin,
     P: DeserializeOwned,
 {
-    inbound.lines().map(|line_result| -> ChatResult<P> {
-        let line = line_result?;
-        let parsed = serde_json::from_str::<P>(&line)?;
+    // Boxed, so that callers can poll it without pinning it themselves.
+    Box::pin(futures::stream::unfold(
+        Some(inbound),
+        |inbound| async move {
+            let mut inbound = inbound?;
+
+            match read_line(&mut inbound).await {
+                Ok(Some(line)) => {
+                    let parsed = serde_json::from_slice::<P>(&line).map_err(ChatError::from);
+                    Some((parsed, Some(inbound)))
+                }
+                Ok(None) => None,
+                Err(error) => Some((Err(error), None)),
+            }
+        },
+    ))
+}
+
+/// Read one line from 'inbound', without its line ending, or return 'None'
+/// at the end of the stream.
+async fn read_line<S>(inbound: &mut S) -> ChatResult<Option<Vec<u8>>>
+where
+    S: AsyncBufRead + Unpin,
+{
+    // Leave room for a "\r\n" after the longest line allowed.
+    let limit = MAX_FRAME_SIZE as u64 + 2;
+    let mut line = Vec::new();
+
+    if inbound.take(limit).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    if line.len() > MAX_FRAME_SIZE {
+        return Err(format!("Line exceeds the limit of {MAX_FRAME_SIZE} bytes").into());
+    }
+
+    Ok(Some(line))
+}
+
+#[test]
+fn test_json_line_limit() {
+    use crate::FromClient;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+
+    block_on(async {
+        let mut buffer = Vec::new();
+        send_as_json(&mut buffer, &FromClient::ListGroups)
+            .await
+            .unwrap();
+        buffer.extend_from_slice(b"{\"Ping\":null}\r\n");
+        buffer.extend(std::iter::repeat_n(b' ', MAX_FRAME_SIZE + 1));
+        buffer.extend_from_slice(b"\n{\"Ping\":null}\n");
 
-        Ok(parsed)
-    })
+        let mut packets = receive_as_json::<_, FromClient>(Cursor::new(buffer));
+        assert_eq!(
+            packets.next().await.unwrap().unwrap(),
+            FromClient::ListGroups
+        );
+        assert_eq!(packets.next().await.unwrap().unwrap(), FromClient::Ping);
+        assert!(packets.next().await.unwrap().is_err());
+        assert!(packets.next().await.is_none());
+    });
 }